@@ -6,6 +6,12 @@
 .set MAGIC, 0x1BADB002
 .set CHECKSUM, -(MAGIC + FLAGS)
 
+.set MB2_MAGIC, 0xE85250D6
+.set MB2_ARCH_I386, 0
+.set MB2_HEADER_LENGTH, _multiboot2_header_end - _multiboot2_header
+.set MB2_CHECKSUM, -(MB2_MAGIC + MB2_ARCH_I386 + MB2_HEADER_LENGTH)
+.set MB2_TAG_END, 0
+
 .set IA32_SYSENTER_CS, 0x174
 .set IA32_SYSENTER_EIP, 0x176
 .set IA32_SYSENTER_ESP, 0x175
@@ -16,6 +22,18 @@
 .long FLAGS
 .long CHECKSUM
 
+/* the loader decides which of both headers it honours */
+.align 8
+_multiboot2_header:
+.long MB2_MAGIC
+.long MB2_ARCH_I386
+.long MB2_HEADER_LENGTH
+.long MB2_CHECKSUM
+.hword MB2_TAG_END
+.hword 0
+.long 8
+_multiboot2_header_end:
+
 .section .bss
 .align 16
 _stack_bottom:
@@ -99,6 +117,7 @@ _gdt_ptr:
 .long _gdt
 
 .align 16
+.global _tss
 _tss:
 .long 0
 .long _tss_stack_top
@@ -110,35 +129,40 @@ _tss:
 _multiboot_info:
 .long 0
 
-_counter:
+/* tells which protocol booted the kernel */
+.global _multiboot_magic
+_multiboot_magic:
 .long 0
 
 .section .text
 .code32
 
 _sysenter_handler:
-        mov eax, [_counter]
-        inc eax
-        mov [_counter], eax
-        /* ecx > esp */
+        /* ecx -> esp */
         /* edx -> eip */
+        push ecx
+        push edx
+        /* syscalls can be preempted */
+        sti
+        push edi
+        push esi
+        push ebx
+        push eax
+        call handle_syscall
+        add esp, 16
+        cli
+        pop edx
+        pop ecx
         sti
         sysexit
 
-.global kcall
-kcall:
-        mov ecx, esp
-        lea edx, 1f
-        sysenter
-        1:
-        ret
-
 .global _start
 _start:
         cli
         cld
 
         mov _multiboot_info, ebx
+        mov _multiboot_magic, eax
 
         /* setting GDT */
         lgdt _gdt_ptr
@@ -232,3 +256,4 @@ _restore_kernel_thread:
         sub esp, 12
         iretd
 .size restore_thread, . - restore_thread
+
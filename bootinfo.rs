@@ -0,0 +1,194 @@
+use core::fmt::{Debug, Formatter, Result};
+use core::mem;
+use crate::multiboot::{self, ElfSections, Framebuffer, MemoryRegion, MultibootInformation};
+use crate::multiboot2::{self, Multiboot2Information};
+
+extern "C" {
+    static _multiboot_info: u32;
+    static _multiboot_magic: u32;
+}
+
+// What the kernel needs from the bootloader, independent of the protocol
+pub enum BootInfo {
+    Multiboot(&'static MultibootInformation),
+    Multiboot2(Multiboot2Information),
+}
+
+pub struct BootModule {
+    start: u32,
+    end: u32,
+    cmdline: &'static [u8],
+}
+
+impl BootModule {
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    pub fn data(&self) -> &'static [u8] {
+        multiboot::bytes(self.start, self.end.saturating_sub(self.start))
+    }
+
+    pub fn cmdline(&self) -> &'static [u8] {
+        self.cmdline
+    }
+}
+
+pub enum BootModules {
+    Multiboot(core::slice::Iter<'static, multiboot::Module>),
+    Multiboot2(multiboot2::Modules),
+}
+
+impl Iterator for BootModules {
+    type Item = BootModule;
+
+    fn next(&mut self) -> Option<BootModule> {
+        match self {
+            BootModules::Multiboot(modules) => modules.next().map(|module| BootModule {
+                start: module.start(),
+                end: module.end(),
+                cmdline: module.cmdline(),
+            }),
+            BootModules::Multiboot2(modules) => modules.next().map(|module| BootModule {
+                start: module.start(),
+                end: module.end(),
+                cmdline: module.cmdline(),
+            }),
+        }
+    }
+}
+
+pub enum MemoryRegions {
+    Multiboot(multiboot::MemoryMap),
+    Multiboot2(multiboot2::MemoryMap),
+}
+
+impl Iterator for MemoryRegions {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<MemoryRegion> {
+        match self {
+            MemoryRegions::Multiboot(map) => map.next(),
+            MemoryRegions::Multiboot2(map) => map.next(),
+        }
+    }
+}
+
+// Tells from the magic value in eax which protocol booted the kernel
+pub fn get() -> BootInfo {
+    unsafe {
+        match _multiboot_magic {
+            multiboot::MULTIBOOT_BOOTLOADER_MAGIC =>
+                BootInfo::Multiboot(multiboot::from_addr(_multiboot_info)),
+            multiboot2::MULTIBOOT2_BOOTLOADER_MAGIC =>
+                BootInfo::Multiboot2(Multiboot2Information::from_addr(_multiboot_info)),
+            magic => panic!("unknown bootloader magic 0x{:08X}", magic),
+        }
+    }
+}
+
+impl BootInfo {
+    pub fn cmdline(&self) -> Option<&'static [u8]> {
+        match self {
+            BootInfo::Multiboot(mbi) => mbi.cmdline(),
+            BootInfo::Multiboot2(mbi) => mbi.cmdline(),
+        }
+    }
+
+    // Multiboot loaders like Qemu and GRUB put the path of the kernel image
+    // in front of the parameters, Multiboot2 ones leave it out
+    pub fn cmdline_has_image(&self) -> bool {
+        match self {
+            BootInfo::Multiboot(_) => true,
+            BootInfo::Multiboot2(_) => false,
+        }
+    }
+
+    pub fn boot_loader_name(&self) -> Option<&'static [u8]> {
+        match self {
+            BootInfo::Multiboot(mbi) => mbi.boot_loader_name(),
+            BootInfo::Multiboot2(mbi) => mbi.boot_loader_name(),
+        }
+    }
+
+    // Lower and upper memory in KiB
+    pub fn memory_bounds(&self) -> Option<(u32, u32)> {
+        match self {
+            BootInfo::Multiboot(mbi) => mbi.memory_bounds(),
+            BootInfo::Multiboot2(mbi) => mbi.memory_bounds(),
+        }
+    }
+
+    pub fn memory_map(&self) -> Option<MemoryRegions> {
+        match self {
+            BootInfo::Multiboot(mbi) => mbi.memory_map().map(MemoryRegions::Multiboot),
+            BootInfo::Multiboot2(mbi) => mbi.memory_map().map(MemoryRegions::Multiboot2),
+        }
+    }
+
+    pub fn modules(&self) -> BootModules {
+        match self {
+            BootInfo::Multiboot(mbi) => BootModules::Multiboot(mbi.modules()),
+            BootInfo::Multiboot2(mbi) => BootModules::Multiboot2(mbi.modules()),
+        }
+    }
+
+    pub fn elf_sections(&self) -> Option<ElfSections> {
+        match self {
+            BootInfo::Multiboot(mbi) => mbi.elf_sections(),
+            BootInfo::Multiboot2(mbi) => mbi.elf_sections(),
+        }
+    }
+
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        match self {
+            BootInfo::Multiboot(mbi) => mbi.framebuffer(),
+            BootInfo::Multiboot2(mbi) => mbi.framebuffer(),
+        }
+    }
+
+    // Only Multiboot2 loaders pass the ACPI RSDP
+    pub fn rsdp(&self) -> Option<&'static [u8]> {
+        match self {
+            BootInfo::Multiboot(_) => None,
+            BootInfo::Multiboot2(mbi) => mbi.rsdp(),
+        }
+    }
+
+    // Calls f with the start and end of everything the bootloader handed over
+    pub fn for_each_reserved(&self, mut f: impl FnMut(u32, u32)) {
+        match self {
+            BootInfo::Multiboot(mbi) => {
+                let addr = *mbi as *const MultibootInformation as u32;
+                f(addr, addr + mem::size_of::<MultibootInformation>() as u32);
+                let ranges = [mbi.cmdline_range(), mbi.modules_range(), mbi.memory_map_range()];
+                for (start, end) in ranges.into_iter().flatten() {
+                    f(start, end);
+                }
+                for module in mbi.modules() {
+                    if let Some((start, end)) = module.cmdline_range() {
+                        f(start, end);
+                    }
+                }
+            },
+            // Strings and maps are all part of the structure itself
+            BootInfo::Multiboot2(mbi) => f(mbi.addr(), mbi.addr() + mbi.total_size()),
+        }
+        for module in self.modules() {
+            f(module.start(), module.end());
+        }
+    }
+}
+
+impl Debug for BootInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            BootInfo::Multiboot(mbi) => mbi.fmt(f),
+            BootInfo::Multiboot2(mbi) => mbi.fmt(f),
+        }
+    }
+}
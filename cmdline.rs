@@ -0,0 +1,207 @@
+use core::fmt::{Result, Write};
+use core::ptr::addr_of;
+use crate::multiboot::Bytes;
+use crate::spinlock::SpinLock;
+
+// Parameters are collected by the linker from every kernel_param! use
+pub struct KernelParam {
+    name: &'static str,
+    // Both get the text after '=' or None for a bare flag
+    set: fn(Option<&'static str>) -> bool,
+    valid: fn(Option<&'static str>) -> bool,
+}
+
+impl KernelParam {
+    pub const fn new(name: &'static str,
+                     set: fn(Option<&'static str>) -> bool,
+                     valid: fn(Option<&'static str>) -> bool) -> KernelParam {
+        KernelParam { name, set, valid }
+    }
+}
+
+extern "C" {
+    static _kernel_params_start: KernelParam;
+    static _kernel_params_end: KernelParam;
+}
+
+fn params() -> &'static [KernelParam] {
+    unsafe {
+        let start = addr_of!(_kernel_params_start);
+        let end = addr_of!(_kernel_params_end);
+        let count = end.offset_from(start) as usize;
+        core::slice::from_raw_parts(start, count)
+    }
+}
+
+pub trait ParamValue: Sized {
+    fn parse(value: Option<&'static str>) -> Option<Self>;
+}
+
+// A bare flag switches it on
+impl ParamValue for bool {
+    fn parse(value: Option<&'static str>) -> Option<bool> {
+        match value {
+            None | Some("1") | Some("y") | Some("yes") | Some("on") | Some("true") => Some(true),
+            Some("0") | Some("n") | Some("no") | Some("off") | Some("false") => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl ParamValue for &'static str {
+    fn parse(value: Option<&'static str>) -> Option<&'static str> {
+        value
+    }
+}
+
+macro_rules! impl_param_value_int {
+    ($($ty:ty),*) => {
+        $(
+            // Decimal or hexadecimal with a 0x prefix
+            impl ParamValue for $ty {
+                fn parse(value: Option<&'static str>) -> Option<$ty> {
+                    let value = value?;
+                    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+                        Some(hex) => <$ty>::from_str_radix(hex, 16).ok(),
+                        None => value.parse().ok(),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_param_value_int!(u8, u16, u32, u64, usize, i32);
+
+// Declares a static holding the default value that is overwritten when the
+// parameter is given on the command line, e.g.
+// kernel_param!(static mut BAUD: u32 = 115200, "serial.baud");
+#[macro_export]
+macro_rules! kernel_param {
+    ($vis:vis static mut $var:ident: $ty:ty = $default:expr, $name:literal) => {
+        $vis static mut $var: $ty = $default;
+        const _: () = {
+            #[used]
+            #[link_section = ".kernel_params"]
+            static PARAM: $crate::cmdline::KernelParam = $crate::cmdline::KernelParam::new(
+                $name,
+                |value| match <$ty as $crate::cmdline::ParamValue>::parse(value) {
+                    Some(value) => {
+                        unsafe {
+                            $var = value;
+                        }
+                        true
+                    },
+                    None => false,
+                },
+                |value| <$ty as $crate::cmdline::ParamValue>::parse(value).is_some());
+        };
+    };
+}
+
+struct Cmdline {
+    line: &'static [u8],
+    image: &'static [u8],
+    // What follows the image
+    params: &'static [u8],
+}
+
+static CMDLINE: SpinLock<Cmdline> = SpinLock::new(Cmdline {
+    line: &[],
+    image: &[],
+    params: &[],
+});
+
+// Splits at spaces outside double quotes
+struct Words {
+    rest: &'static [u8],
+}
+
+impl Iterator for Words {
+    type Item = &'static [u8];
+
+    fn next(&mut self) -> Option<&'static [u8]> {
+        let start = self.rest.iter().position(|b| !b.is_ascii_whitespace())?;
+        let rest = &self.rest[start..];
+        let mut quoted = false;
+        let mut end = rest.len();
+        for (i, b) in rest.iter().enumerate() {
+            if *b == b'"' {
+                quoted = !quoted;
+            } else if b.is_ascii_whitespace() && !quoted {
+                end = i;
+                break;
+            }
+        }
+        self.rest = &rest[end..];
+        Some(&rest[..end])
+    }
+}
+
+fn args() -> Words {
+    Words { rest: CMDLINE.lock().params }
+}
+
+fn split(arg: &'static str) -> (&'static str, Option<&'static str>) {
+    match arg.split_once('=') {
+        Some((name, value)) => {
+            let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+            (name, Some(value))
+        },
+        None => (arg, None),
+    }
+}
+
+fn find(name: &str) -> Option<&'static KernelParam> {
+    params().iter().find(|param| param.name == name)
+}
+
+// Runs before anything can be printed, problems are reported later by
+// report(), the boot protocol tells whether the image path comes first
+pub fn init(cmdline: &'static [u8], has_image: bool) {
+    let mut words = Words { rest: cmdline };
+    let image = if has_image { words.next().unwrap_or(&[]) } else { &[] };
+    *CMDLINE.lock() = Cmdline {
+        line: cmdline,
+        image,
+        params: words.rest,
+    };
+    for arg in args() {
+        if let Ok(arg) = core::str::from_utf8(arg) {
+            let (name, value) = split(arg);
+            if let Some(param) = find(name) {
+                (param.set)(value);
+            }
+        }
+    }
+}
+
+pub fn report(out: &mut dyn Write) -> Result {
+    for arg in args() {
+        let arg = match core::str::from_utf8(arg) {
+            Ok(arg) => arg,
+            Err(_) => {
+                write!(out, "invalid kernel parameter {}\n", Bytes(arg))?;
+                continue;
+            },
+        };
+        let (name, value) = split(arg);
+        match find(name) {
+            Some(param) if !(param.valid)(value) =>
+                write!(out, "invalid value for kernel parameter {}\n", arg)?,
+            Some(_) => {},
+            None => write!(out, "unknown kernel parameter {}\n", arg)?,
+        }
+    }
+    Ok(())
+}
+
+// The command line as the bootloader passed it, like /proc/cmdline
+pub fn cmdline() -> &'static [u8] {
+    CMDLINE.lock().line
+}
+
+// Path of the kernel image if the bootloader passed it
+pub fn image() -> &'static [u8] {
+    CMDLINE.lock().image
+}
@@ -0,0 +1,118 @@
+use core::arch::asm;
+
+pub const X86_CR0_PG: u32 = 1 << 31;
+pub const X86_CR0_WP: u32 = 1 << 16;
+
+pub const X86_CR4_PSE: u32 = 1 << 4;
+pub const X86_CR4_PGE: u32 = 1 << 7;
+
+// CPUID leaf 1 feature bits in EDX
+pub const CPUID_1_EDX_PSE: u32 = 1 << 3;
+pub const CPUID_1_EDX_TSC: u32 = 1 << 4;
+pub const CPUID_1_EDX_PGE: u32 = 1 << 13;
+
+pub const IA32_SYSENTER_CS: u32 = 0x174;
+pub const IA32_SYSENTER_ESP: u32 = 0x175;
+pub const IA32_SYSENTER_EIP: u32 = 0x176;
+
+pub fn rdmsr(msr: u32) -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi);
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+pub fn wrmsr(msr: u32, val: u64) {
+    let lo = val as u32;
+    let hi = (val >> 32) as u32;
+    unsafe {
+        asm!("wrmsr", in("ecx") msr, in("eax") lo, in("edx") hi);
+    }
+}
+
+pub fn read_eflags() -> u32 {
+    let val: u32;
+    unsafe {
+        asm!("pushfd", "pop {}", out(reg) val);
+    }
+    val
+}
+
+pub fn read_cr0() -> u32 {
+    let val: u32;
+    unsafe {
+        asm!("mov {}, cr0", out(reg) val);
+    }
+    val
+}
+
+pub fn write_cr0(val: u32) {
+    unsafe {
+        asm!("mov cr0, {}", in(reg) val);
+    }
+}
+
+pub fn read_cr2() -> u32 {
+    let val: u32;
+    unsafe {
+        asm!("mov {}, cr2", out(reg) val);
+    }
+    val
+}
+
+pub fn read_cr3() -> u32 {
+    let val: u32;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) val);
+    }
+    val
+}
+
+pub fn write_cr3(val: u32) {
+    unsafe {
+        asm!("mov cr3, {}", in(reg) val);
+    }
+}
+
+pub fn read_cr4() -> u32 {
+    let val: u32;
+    unsafe {
+        asm!("mov {}, cr4", out(reg) val);
+    }
+    val
+}
+
+pub fn write_cr4(val: u32) {
+    unsafe {
+        asm!("mov cr4, {}", in(reg) val);
+    }
+}
+
+pub fn invlpg(addr: u32) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) addr);
+    }
+}
+
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") lo, out("edx") hi);
+    }
+    (hi as u64) << 32 | lo as u64
+}
+
+// Returns eax, ebx, ecx and edx, ebx is saved by hand as LLVM may use it
+pub fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("mov {0}, ebx",
+             "cpuid",
+             "xchg {0}, ebx",
+             out(reg) ebx, inout("eax") leaf => eax, inout("ecx") 0 => ecx, out("edx") edx);
+    }
+    (eax, ebx, ecx, edx)
+}
@@ -0,0 +1,159 @@
+use core::mem;
+use crate::paging::{self, AddressSpace, MapError};
+use crate::pmm;
+use crate::sched;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+const ELF_CLASS_32: u8 = 1;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_VERSION_CURRENT: u32 = 1;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_MACHINE_386: u16 = 3;
+
+const ELF_PT_LOAD: u32 = 1;
+const ELF_PF_W: u32 = 1 << 1;
+
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u32,
+    phoff: u32,
+    shoff: u32,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+const SA_ELF_HEADER_SIZE: usize =
+    (mem::size_of::<ElfHeader>() == 52) as usize - 1;
+
+#[repr(C)]
+struct ProgramHeader {
+    seg_type: u32,
+    offset: u32,
+    vaddr: u32,
+    paddr: u32,
+    filesz: u32,
+    memsz: u32,
+    flags: u32,
+    align: u32,
+}
+const SA_PROGRAM_HEADER_SIZE: usize =
+    (mem::size_of::<ProgramHeader>() == 32) as usize - 1;
+
+#[derive(Debug)]
+pub enum ElfError {
+    Truncated,
+    NotElf,
+    Unsupported,
+    BadSegment,
+    BadEntry,
+    OutOfMemory,
+}
+
+impl From<MapError> for ElfError {
+    fn from(err: MapError) -> ElfError {
+        match err {
+            MapError::OutOfMemory => ElfError::OutOfMemory,
+            MapError::AlreadyMapped | MapError::HugePage => ElfError::BadSegment,
+        }
+    }
+}
+
+// Images are not necessarily aligned in memory
+fn read<T>(image: &[u8], offset: u32) -> Result<T, ElfError> {
+    let end = (offset as usize).checked_add(mem::size_of::<T>()).ok_or(ElfError::Truncated)?;
+    if end > image.len() {
+        return Err(ElfError::Truncated);
+    }
+    unsafe { Ok(core::ptr::read_unaligned(image.as_ptr().add(offset as usize) as *const T)) }
+}
+
+fn check_header(header: &ElfHeader) -> Result<(), ElfError> {
+    if header.ident[..4] != ELF_MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    if header.ident[4] != ELF_CLASS_32 || header.ident[5] != ELF_DATA_LSB
+        || header.version != ELF_VERSION_CURRENT || header.elf_type != ELF_TYPE_EXEC
+        || header.machine != ELF_MACHINE_386 {
+        return Err(ElfError::Unsupported);
+    }
+    if (header.phentsize as usize) < mem::size_of::<ProgramHeader>() {
+        return Err(ElfError::Unsupported);
+    }
+    Ok(())
+}
+
+fn map_segment_page(space: &AddressSpace, page: u32, flags: u32) -> Result<(), ElfError> {
+    // Segments may share a page at their boundary, the page then gets the
+    // union of their permissions
+    if space.translate(page).is_some() {
+        if flags & paging::X86_PTE_WRITABLE != 0 {
+            let frame = space.unmap_page(page).ok_or(ElfError::BadSegment)?;
+            space.map_page(page, frame, flags)?;
+        }
+        return Ok(());
+    }
+    let frame = paging::alloc_zeroed_frame().ok_or(ElfError::OutOfMemory)?;
+    if let Err(err) = space.map_page(page, frame, flags) {
+        pmm::free_frame(frame);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+fn load_segment(space: &AddressSpace, image: &[u8], ph: &ProgramHeader) -> Result<(), ElfError> {
+    if ph.memsz == 0 {
+        return Ok(());
+    }
+    let end = ph.vaddr.checked_add(ph.memsz).ok_or(ElfError::BadSegment)?;
+    // The top of user space is left to the thread stacks
+    if ph.filesz > ph.memsz || ph.vaddr < paging::USER_START || end > sched::USER_STACKS_BOTTOM {
+        return Err(ElfError::BadSegment);
+    }
+    let file_end = ph.offset.checked_add(ph.filesz).ok_or(ElfError::Truncated)?;
+    if file_end as usize > image.len() {
+        return Err(ElfError::Truncated);
+    }
+
+    let mut flags = paging::X86_PTE_USER;
+    if ph.flags & ELF_PF_W != 0 {
+        flags |= paging::X86_PTE_WRITABLE;
+    }
+    let mut page = ph.vaddr & !(paging::PAGE_SIZE - 1);
+    while page < end {
+        map_segment_page(space, page, flags)?;
+        page += paging::PAGE_SIZE;
+    }
+    // The rest up to memsz stays zero from the fresh frames
+    space.write(ph.vaddr, &image[ph.offset as usize..file_end as usize]);
+    Ok(())
+}
+
+// Maps the loadable segments of an executable into the address space and
+// returns its entry point, whatever was mapped before a failure is released
+// together with the address space
+pub fn load(space: &AddressSpace, image: &[u8]) -> Result<u32, ElfError> {
+    let header: ElfHeader = read(image, 0)?;
+    check_header(&header)?;
+    for i in 0..header.phnum as u32 {
+        let offset = i.checked_mul(header.phentsize as u32)
+            .and_then(|offset| offset.checked_add(header.phoff))
+            .ok_or(ElfError::Truncated)?;
+        let ph: ProgramHeader = read(image, offset)?;
+        if ph.seg_type == ELF_PT_LOAD {
+            load_segment(space, image, &ph)?;
+        }
+    }
+    if header.entry < paging::USER_START || header.entry >= paging::USER_END
+        || space.translate(header.entry).is_none() {
+        return Err(ElfError::BadEntry);
+    }
+    Ok(header.entry)
+}
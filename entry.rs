@@ -1,223 +1,124 @@
-use core::fmt::{Write, Debug, Formatter, Result};
-use core::mem;
+use core::fmt::Write;
+use core::time::Duration;
+use core::ptr::addr_of;
+use crate::bootinfo::{self, BootInfo};
 use crate::idt;
+use crate::cmdline;
+use crate::initrd::{self, FileType};
+use crate::multiboot::{Bytes, MemoryType};
+use crate::paging;
 use crate::pic;
-use crate::serial;
+use crate::pmm;
+use crate::process;
+use crate::rtc;
+use crate::serial::{self, Serial};
 use crate::vga::Vga;
 use crate::sched;
+use crate::time;
 
 extern "C" {
-    static _multiboot_info: &'static MultibootInformation;
+    static _kernel_start: u8;
+    static _kernel_end: u8;
 }
 
-#[repr(C)]
-pub struct MultibootInformation {
-    flags: u32,
-    mem_lower: u32,
-    mem_upper: u32,
-    boot_device: u32,
-    cmdline: u32,
-    mods_count: u32,
-    mods_addr: u32,
-    syms: [u32; 4],
-    mmap_length: u32,
-    mmap_addr: u32,
-    drives_length: u32,
-    drives_addr: u32,
-    config_table: u32,
-    boot_loader_name: u32,
-    apm_table: u32,
-    vbe_control_info: u32,
-    vbe_mode_info: u32,
-    vbe_mode: u16,
-    vbe_interface_seg: u16,
-    vbe_interface_off: u16,
-    vbe_interface_len: u16,
-    framebuffer_addr: u64,
-    framebuffer_pitch: u32,
-    framebuffer_width: u32,
-    framebuffer_height: u32,
-    framebuffer_bpp: u8,
-    framebuffer_type: u8,
-    color_info: [u8; 6],
-}
-const SA_MULTIBOOT_INFORMATION_SIZE: usize =
-    (mem::size_of::<MultibootInformation>() == 116) as usize - 1;
-
-#[repr(C)]
-struct MultibootModule {
-    mod_start: u32,
-    mod_end: u32,
-    cmdline: u32,
-    reserved: u32,
-}
-
-#[repr(C)]
-struct MultibootMemory {
-    size: u32,
-    base_addr_low: u32,
-    base_addr_high: u32,
-    length_low: u32,
-    length_high: u32,
-    mem_type: u32,
-}
-const SA_MULTIBOOT_MEMORY_SIZE: usize =
-    (mem::size_of::<MultibootMemory>() == 24) as usize - 1;
-
-impl MultibootMemory {
-    fn base_addr(&self) -> u64 {
-        let mut ret = self.base_addr_high as u64;
-        ret <<= 32;
-        ret |= self.base_addr_low as u64;
-        ret
+fn init_frame_allocator(boot_info: &BootInfo) {
+    if let Some(map) = boot_info.memory_map() {
+        for mem in map.filter(|mem| mem.mem_type() == MemoryType::Available) {
+            pmm::add_free_region(mem.base_addr(), mem.length());
+        }
+    } else if let Some((lower, upper)) = boot_info.memory_bounds() {
+        pmm::add_free_region(0, lower as u64 * 1024);
+        pmm::add_free_region(0x100000, upper as u64 * 1024);
+    } else {
+        panic!("bootloader provided no memory information");
     }
 
-    fn length(&self) -> u64 {
-        let mut ret = self.length_high as u64;
-        ret <<= 32;
-        ret |= self.length_low as u64;
-        ret
+    // Real mode IVT, BIOS data and the VGA buffer
+    pmm::reserve_region(0, 0x100000);
+    unsafe {
+        let start = addr_of!(_kernel_start) as u64;
+        let end = addr_of!(_kernel_end) as u64;
+        pmm::reserve_region(start, end);
     }
 
-    fn end_addr(&self) -> u64 {
-        self.base_addr() + self.length()
-    }
+    // Everything the bootloader handed over must survive as well
+    boot_info.for_each_reserved(|start, end| pmm::reserve_region(start as u64, end as u64));
 }
 
-unsafe fn slice_from_cstr(s: *const u8) -> &'static [u8] {
-    let mut count = 0usize;
+extern "C" fn kernel_thread_proc(_arg: usize)
+{
     loop {
-        if *s.add(count) == 0 {
-            break;
-        }
-        count += 1;
+        serial::write_str("1");
+        sched::sleep(Duration::from_millis(100));
     }
-    core::slice::from_raw_parts(s, count)
 }
 
-fn mem_type_to_str(mem_type: u32) -> &'static str {
-    match mem_type {
-        1 => "available",
-        3 => "ACPI",
-        4 => "hibernation",
-        5 => "defective",
-        _ => "reserved",
+// Ctrl-T on the serial port lists the threads like SIGINFO on BSD
+const SERIAL_THREAD_DUMP: u8 = 0x14;
+
+// Shows what arrives on the serial port on the screen
+extern "C" fn serial_echo_proc(_arg: usize)
+{
+    let mut vga = Vga::new();
+    loop {
+        match serial::read_byte() {
+            SERIAL_THREAD_DUMP => sched::dump_threads(&mut Serial).unwrap(),
+            b => write!(vga, "{}", b as char).unwrap(),
+        }
     }
 }
 
-impl Debug for MultibootInformation {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        f.write_str("MB:\n")?;
-        f.write_fmt(format_args!(
-                "flags 0x{:08X}\n", self.flags))?;
+const INITRD_TAG: &[u8] = b"initrd";
 
-        if self.flags & (1 << 0) != 0 {
-            f.write_fmt(format_args!(
-                    "range 0x{:08X} - 0x{:08X}\n",
-                    self.mem_lower, self.mem_upper))?;
-        }
+// tty0 is the VGA text screen, ttyS0 the first serial port
+crate::kernel_param!(static mut CONSOLE: &'static str = "tty0", "console");
+// Program in the initrd that is started after the modules
+crate::kernel_param!(static mut INIT: &'static str = "", "init");
+// Console messages below this level are shown like on Linux, anything under
+// 7 hides the informational boot messages and keeps warnings and errors
+crate::kernel_param!(static mut LOGLEVEL: u8 = 7, "loglevel");
 
-        if self.flags & (1 << 1) != 0 {
-            f.write_fmt(format_args!(
-                    "boot device 0x{:02X}\n", self.boot_device))?;
-        }
+const LOGLEVEL_INFO: u8 = 6;
 
-        if self.flags & (1 << 2) != 0 {
-            let cmdline = self.cmdline as *const u8;
-            let cmdline = unsafe { slice_from_cstr(cmdline) };
-            f.write_str(core::str::from_utf8(cmdline).unwrap())?;
-            f.write_str("\n")?;
-        }
+fn log_info() -> bool {
+    unsafe { LOGLEVEL > LOGLEVEL_INFO }
+}
 
-        if self.flags & (1 << 3) != 0 {
-            let modules = unsafe { core::slice::from_raw_parts(
-                self.mods_addr as *const MultibootModule,
-                self.mods_count as usize) };
-            for module in modules {
-                let cmdline = module.cmdline as *const u8;
-                let cmdline = unsafe { slice_from_cstr(cmdline) };
-                f.write_fmt(format_args!(
-                        "mod 0x{:08X} - 0x{:08X} {}\n",
-                        module.mod_start, module.mod_end,
-                        core::str::from_utf8(cmdline).unwrap()))?;
+// A module tagged initrd in its cmdline holds the initial ramdisk, every
+// other module is a user program and its cmdline holds the arguments
+fn start_modules(boot_info: &BootInfo, console: &mut dyn Write) {
+    for module in boot_info.modules() {
+        let image = module.data();
+        let cmdline = module.cmdline();
+        if cmdline.split(|c| *c == b' ').any(|word| word == INITRD_TAG) {
+            match initrd::init(image) {
+                Ok(()) if log_info() => write!(console, "initrd: {} entries\n", initrd::files().len()).unwrap(),
+                Ok(()) => {},
+                Err(err) => write!(console, "initrd not loaded: {:?}\n", err).unwrap(),
             }
-        }
-
-        if self.flags & (1 << 4) != 0 || self.flags & (1 << 5) != 0 {
-            // self.syms;
-        }
-
-        if self.flags & (1 << 6) != 0 {
-            unsafe {
-                let mut sum_size = 0u32;
-                let mut ptr = self.mmap_addr as *const MultibootMemory;
-                while sum_size < self.mmap_length {
-                    f.write_fmt(format_args!(
-                            "mem 0x{:016X} - 0x{:016X} {}\n",
-                            (*ptr).base_addr(), (*ptr).end_addr(),
-                            mem_type_to_str((*ptr).mem_type)))?;
-                    let size = (*ptr).size + 4;
-                    ptr = (ptr as *const u8).add(size as usize) as *const MultibootMemory;
-                    sum_size += size;
-                }
+        } else {
+            match process::exec(image, cmdline) {
+                // Nothing waits for it, the process goes away once it exits
+                Ok(id) => sched::detach(id),
+                Err(err) => write!(console, "mod 0x{:08X} not started: {:?}\n", module.start(), err).unwrap(),
             }
         }
-
-        if self.flags & (1 << 7) != 0 {
-            // self.drives_length;
-            // self.drives_addr;
-        }
-
-        if self.flags & (1 << 8) != 0 {
-            // self.config_table;
-        }
-
-        if self.flags & (1 << 9) != 0 {
-            // self.boot_loader_name;
-        }
-
-        if self.flags & (1 << 10) != 0 {
-            // self.apm_table;
-        }
-
-        if self.flags & (1 << 11) != 0 {
-            // vbe
-        }
-
-        if self.flags & (1 << 12) != 0 {
-            // framebuffer
-        }
-
-        Ok(())
     }
 }
 
-fn busy_wait() {
-    let mut i = 0;
-    while i < 1000000 {
-        i += 1;
+fn start_init(console: &mut dyn Write) {
+    let path = unsafe { INIT };
+    if path.is_empty() {
+        return;
     }
-}
-
-fn kernel_thread_proc()
-{
-    loop {
-        serial::write_str("1");
-        busy_wait();
-    }
-}
-
-extern "C" {
-    fn kcall() -> u32;
-}
-
-fn user_thread_proc()
-{
-    let mut vga = Vga::new();
-    loop {
-        let x = unsafe { kcall() };
-        write!(vga, "{}", x).unwrap();
-        busy_wait();
+    match initrd::lookup(path) {
+        Some(file) if file.file_type() == FileType::File => {
+            match process::exec(file.data(), path.as_bytes()) {
+                Ok(id) => sched::detach(id),
+                Err(err) => write!(console, "init {} not started: {:?}\n", path, err).unwrap(),
+            }
+        },
+        _ => write!(console, "init {} not found\n", path).unwrap(),
     }
 }
 
@@ -225,14 +126,51 @@ fn user_thread_proc()
 pub fn kernel_main() -> ! {
     idt::setup_idt();
     pic::remap(0x20, 0x28);
-    pic::mask(0xEC, 0xFF);
+    // Timer, keyboard, cascade, COM1 and the RTC
+    pic::mask(0xE8, 0xFE);
+    let boot_info = bootinfo::get();
+    cmdline::init(boot_info.cmdline().unwrap_or(&[]), boot_info.cmdline_has_image());
     serial::serial_init();
     serial::write_str("Booting kernel...\n");
     let mut vga = Vga::new();
     vga.clear_screen();
-    write!(vga, "{:?}", unsafe { _multiboot_info }).unwrap();
-    sched::init_scheduler();
-    sched::create_kernel_thread(kernel_thread_proc as *const ());
-    sched::create_user_thread(user_thread_proc as *const ());
+    let console: &mut dyn Write = match unsafe { CONSOLE } {
+        "ttyS0" => &mut Serial,
+        _ => &mut vga,
+    };
+    if log_info() {
+        write!(console, "{:?}", boot_info).unwrap();
+    }
+    if log_info() && !cmdline::image().is_empty() {
+        write!(console, "kernel image {}\n", Bytes(cmdline::image())).unwrap();
+    }
+    cmdline::report(console).unwrap();
+    time::init();
+    if log_info() {
+        write!(console, "timer tick {} ns", time::tick_ns()).unwrap();
+        match time::tsc_hz() {
+            Some(hz) => write!(console, ", tsc {} kHz\n", hz / 1000).unwrap(),
+            None => write!(console, "\n").unwrap(),
+        }
+    }
+    rtc::init();
+    if log_info() {
+        write!(console, "{}\n", rtc::now()).unwrap();
+    }
+    init_frame_allocator(&boot_info);
+    if log_info() {
+        write!(console, "{} of {} frames free\n", pmm::free_frames(), pmm::total_frames()).unwrap();
+    }
+    let unmanaged = pmm::unmanaged_bytes();
+    if unmanaged != 0 {
+        write!(console, "{} MiB above 0x{:08X} not used\n", unmanaged >> 20, paging::IDENTITY_MAP_END).unwrap();
+    }
+    paging::init();
+    sched::create_kernel_thread("ticker", kernel_thread_proc, 0);
+    // Interactive, it has to keep up with the input
+    let echo = sched::create_kernel_thread("serial-echo", serial_echo_proc, 0);
+    sched::set_priority(echo, sched::PRIORITY_MAX);
+    start_modules(&boot_info, console);
+    start_init(console);
     sched::start_scheduler();
 }
@@ -0,0 +1,159 @@
+use crate::paging;
+use crate::pmm;
+use crate::spinlock::IrqSpinLock;
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr::null_mut;
+
+const HEAP_START: usize = paging::KERNEL_HIGH_START as usize;
+const HEAP_MAX_SIZE: usize = 256 * 1024 * 1024;
+const BLOCK_ALIGN: usize = 8;
+const MIN_BLOCK_SIZE: usize = mem::size_of::<FreeBlock>();
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+struct Heap {
+    // Free blocks are kept sorted by address so that neighbours can be merged
+    free_list: *mut FreeBlock,
+    end: usize,
+}
+
+// The blocks are only reached through the lock
+unsafe impl Send for Heap {}
+
+struct KernelHeap {
+    heap: IrqSpinLock<Heap>,
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap {
+    heap: IrqSpinLock::new(Heap {
+        free_list: null_mut(),
+        end: HEAP_START,
+    }),
+};
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+fn block_size(layout: &Layout) -> usize {
+    align_up(layout.size().max(MIN_BLOCK_SIZE), BLOCK_ALIGN)
+}
+
+impl Heap {
+    unsafe fn insert_free(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut next = self.free_list;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        (*block).size = size;
+        (*block).next = next;
+        if !next.is_null() && addr + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.free_list = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    unsafe fn take_fitting(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut block = self.free_list;
+        while !block.is_null() {
+            let start = block as usize;
+            let end = start + (*block).size;
+            let next = (*block).next;
+
+            // A gap in front of the allocation has to hold a free block itself
+            let mut alloc_start = align_up(start, align);
+            if alloc_start != start && alloc_start - start < MIN_BLOCK_SIZE {
+                alloc_start = align_up(start + MIN_BLOCK_SIZE, align);
+            }
+            let alloc_end = alloc_start + size;
+            let tail = end.saturating_sub(alloc_end);
+            if alloc_end <= end && (tail == 0 || tail >= MIN_BLOCK_SIZE) {
+                if prev.is_null() {
+                    self.free_list = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if alloc_start != start {
+                    self.insert_free(start, alloc_start - start);
+                }
+                if tail != 0 {
+                    self.insert_free(alloc_end, tail);
+                }
+                return Some(alloc_start);
+            }
+
+            prev = block;
+            block = next;
+        }
+        None
+    }
+
+    unsafe fn grow(&mut self, min_size: usize) -> bool {
+        let new_end = align_up(self.end + min_size, paging::PAGE_SIZE as usize);
+        if new_end > HEAP_START + HEAP_MAX_SIZE {
+            return false;
+        }
+        let old_end = self.end;
+        while self.end < new_end {
+            let frame = match pmm::alloc_frame() {
+                Some(frame) => frame,
+                None => break,
+            };
+            if paging::map_page(self.end as u32, frame,
+                                paging::X86_PTE_WRITABLE | paging::X86_PTE_GLOBAL).is_err() {
+                pmm::free_frame(frame);
+                break;
+            }
+            self.end += paging::PAGE_SIZE as usize;
+        }
+        if self.end != old_end {
+            self.insert_free(old_end, self.end - old_end);
+        }
+        self.end == new_end
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let size = block_size(&layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+        let mut heap = self.heap.lock();
+        if let Some(addr) = heap.take_fitting(size, align) {
+            return addr as *mut u8;
+        }
+        if !heap.grow(size + align) {
+            return null_mut();
+        }
+        heap.take_fitting(size, align).map_or(null_mut(), |addr| addr as *mut u8)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let size = block_size(&layout);
+        self.heap.lock().insert_free(ptr as usize, size);
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!("kernel heap exhausted allocating {} bytes aligned to {}",
+           layout.size(), layout.align());
+}
@@ -1,13 +1,16 @@
+use crate::cpu;
+use crate::keyboard;
+use crate::pagefault;
+use crate::rtc;
 use crate::sched;
 use crate::serial;
+use crate::syscall;
+use crate::time;
+use crate::timer;
 use crate::pic;
-use crate::vga::Vga;
 use core::arch::asm;
-use core::fmt::Write;
 use core::ptr::addr_of_mut;
 
-static mut VGA: Vga = Vga::new();
-
 extern "C" {
     static mut _idt: u64;
 }
@@ -128,43 +131,76 @@ const X86_EXC_VIRTUALIZATION: u32 = 20;
 const X86_EXC_CONTROL_PROTECTION: u32 = 21;
 
 #[no_mangle]
-extern "C" fn handle_interrupt(int_state: *const u32) {
+extern "C" fn handle_interrupt(int_state: *mut u32) {
     let vec: u32 = unsafe { *int_state.offset(7) };
     let err: u32 = unsafe { *int_state.offset(8) };
     match vec {
         0x20 => {
-            sched::save_current_state(int_state);
+            time::timer_tick();
+            timer::run_expired();
             pic::end_of_interrupt(0);
-            sched::invoke_scheduler();
+            let cs = unsafe { *int_state.offset(X86_INT_STATE_CS as isize) };
+            if sched::timer_tick(cs & 0b11 == 0b11) {
+                sched::save_current_state(int_state);
+                sched::preempt();
+            }
         },
         0x21 => {
-            serial::write_str("k");
+            keyboard::handle_interrupt();
             pic::end_of_interrupt(1);
         },
         0x24 => {
-            let b = serial::get_byte();
-            unsafe { write!(VGA, "{}", b as char).unwrap(); }
-            serial::write_str("4");
+            serial::handle_interrupt();
             pic::end_of_interrupt(4);
         },
+        0x28 => {
+            rtc::handle_interrupt();
+            pic::end_of_interrupt(8);
+        },
+        X86_EXC_PAGE_FAULT => {
+            pagefault::handle_page_fault(int_state, err);
+        },
+        0x80 => {
+            // Same register convention as sysenter, the result goes back in eax
+            unsafe {
+                let num = *int_state.offset(X86_INT_STATE_EAX as isize);
+                let arg1 = *int_state.offset(X86_INT_STATE_EBX as isize);
+                let arg2 = *int_state.offset(X86_INT_STATE_ESI as isize);
+                let arg3 = *int_state.offset(X86_INT_STATE_EDI as isize);
+                *int_state.offset(X86_INT_STATE_EAX as isize) =
+                    syscall::dispatch(num, arg1, arg2, arg3);
+            }
+        },
+        0x81 => {
+            sched::save_current_state(int_state);
+            sched::yield_current();
+        },
         _ => {
-            let thread = sched::current();
             panic!("\
                 interrupt {}, error {}\n\
                 thread:\n\
                 {}",
-                vec, err, thread);
+                vec, err, sched::Registers::from_int_state(int_state));
         },
     }
 }
 
+// P(1) | DPL(00) | 0 | Type(1110), interrupts are disabled on entry
+pub const X86_GATE_INTERRUPT: u16 = 0x8E00;
+// P(1) | DPL(11) | 0 | Type(1111), reachable with int from ring 3, keeps IF
+pub const X86_GATE_USER_TRAP: u16 = 0xEF00;
+
 pub fn setup_irq_handler(idx: u8, handler: *const ()) {
+    setup_gate(idx, handler, X86_GATE_INTERRUPT);
+}
+
+pub fn setup_gate(idx: u8, handler: *const (), flags: u16) {
     let handler = handler as u64;
     let lo = handler & 0xFFFF;
     let hi = ((handler >> 16) & 0xFFFF) << 48;
     let kernel_cs = 0x8u16;
     let cs = (kernel_cs as u64) << 16;
-    let fl = 0x8E00u64 << 32;
+    let fl = (flags as u64) << 32;
     let idt_desc: u64 = lo | cs | fl | hi;
     unsafe {
         let idt = addr_of_mut!(_idt) as *mut u64;
@@ -198,6 +234,9 @@ pub fn setup_idt() {
     setup_irq_handler(0x20, isr_32 as *const ());
     setup_irq_handler(0x21, isr_33 as *const ());
     setup_irq_handler(0x24, isr_36 as *const ());
+    setup_irq_handler(0x28, isr_40 as *const ());
+    setup_gate(0x80, isr_128 as *const (), X86_GATE_USER_TRAP);
+    setup_irq_handler(0x81, isr_129 as *const ());
 }
 
 pub fn enable_interrupts() {
@@ -208,6 +247,20 @@ pub fn disable_interrupts() {
     unsafe { asm!("cli"); }
 }
 
+pub fn interrupts_enabled() -> bool {
+    cpu::read_eflags() & sched::X86_EFLAGS_IF != 0
+}
+
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = interrupts_enabled();
+    disable_interrupts();
+    let ret = f();
+    if enabled {
+        enable_interrupts();
+    }
+    ret
+}
+
 fn halt() {
     unsafe { asm!("hlt"); }
 }
@@ -0,0 +1,304 @@
+use alloc::vec::Vec;
+use crate::spinlock::SpinLock;
+
+// Read-only filesystem over a USTAR or newc cpio archive, the files point
+// straight into the module memory
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_NAME: usize = 0;
+const TAR_NAME_LEN: usize = 100;
+const TAR_SIZE: usize = 124;
+const TAR_SIZE_LEN: usize = 12;
+const TAR_TYPE: usize = 156;
+const TAR_MAGIC: usize = 257;
+const TAR_PREFIX: usize = 345;
+const TAR_PREFIX_LEN: usize = 155;
+const TAR_TYPE_FILE: u8 = b'0';
+const TAR_TYPE_FILE_OLD: u8 = 0;
+const TAR_TYPE_DIRECTORY: u8 = b'5';
+
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_MAGIC_CRC: &[u8] = b"070702";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_FIELD_LEN: usize = 8;
+const CPIO_MODE: usize = 1;
+const CPIO_FILESIZE: usize = 6;
+const CPIO_NAMESIZE: usize = 11;
+const CPIO_TRAILER: &[u8] = b"TRAILER!!!";
+const CPIO_MODE_TYPE_MASK: u32 = 0o170000;
+const CPIO_MODE_DIRECTORY: u32 = 0o040000;
+const CPIO_MODE_FILE: u32 = 0o100000;
+
+#[derive(Debug)]
+pub enum InitrdError {
+    UnknownFormat,
+    Truncated,
+    BadHeader,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+pub struct File {
+    path: &'static str,
+    file_type: FileType,
+    data: &'static [u8],
+}
+
+impl File {
+    pub fn path(&self) -> &'static str {
+        self.path
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.path.rsplit('/').next().unwrap_or(self.path)
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+
+    // Returns the number of bytes read, 0 at or past the end
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        if offset >= self.data.len() {
+            return 0;
+        }
+        let len = buf.len().min(self.data.len() - offset);
+        buf[..len].copy_from_slice(&self.data[offset..offset + len]);
+        len
+    }
+}
+
+pub struct DirEntry {
+    name: &'static str,
+    file_type: FileType,
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+}
+
+// Set once the archive is parsed, the list is never freed so lookups can
+// hand out references to it
+static FILES: SpinLock<&'static [File]> = SpinLock::new(&[]);
+
+// Archives store paths as "./a/b", "/a/b" or "a/b/", the root is ""
+fn normalize(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else {
+            break;
+        }
+    }
+    let path = path.trim_end_matches('/');
+    if path == "." { "" } else { path }
+}
+
+fn field(data: &'static [u8], start: usize, len: usize) -> Result<&'static [u8], InitrdError> {
+    let end = start.checked_add(len).ok_or(InitrdError::Truncated)?;
+    data.get(start..end).ok_or(InitrdError::Truncated)
+}
+
+// String fields end at the first zero byte or fill the whole field
+fn cstr_field(data: &'static [u8], start: usize, len: usize) -> Result<&'static [u8], InitrdError> {
+    let field = field(data, start, len)?;
+    let end = field.iter().position(|b| *b == 0).unwrap_or(len);
+    Ok(&field[..end])
+}
+
+fn parse_number(digits: &[u8], radix: u32) -> Result<usize, InitrdError> {
+    let digits = core::str::from_utf8(digits).map_err(|_| InitrdError::BadHeader)?;
+    let digits = digits.trim_matches(|c| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(digits, radix).map_err(|_| InitrdError::BadHeader)
+}
+
+fn add_file(files: &mut Vec<File>, path: &'static [u8], file_type: FileType, data: &'static [u8]) {
+    // Entries that are not valid UTF-8 cannot be looked up and are left out
+    if let Ok(path) = core::str::from_utf8(path) {
+        let path = normalize(path);
+        if !path.is_empty() {
+            files.push(File { path, file_type, data });
+        }
+    }
+}
+
+fn is_tar(image: &[u8]) -> bool {
+    image.get(TAR_MAGIC..TAR_MAGIC + 5) == Some(b"ustar")
+}
+
+fn parse_tar(image: &'static [u8], files: &mut Vec<File>) -> Result<(), InitrdError> {
+    let mut offset = 0;
+    while offset + TAR_BLOCK_SIZE <= image.len() {
+        let header = &image[offset..offset + TAR_BLOCK_SIZE];
+        // The archive ends with zero blocks
+        if header[TAR_NAME] == 0 {
+            return Ok(());
+        }
+        if !is_tar(header) {
+            return Err(InitrdError::BadHeader);
+        }
+        let size = parse_number(field(header, TAR_SIZE, TAR_SIZE_LEN)?, 8)?;
+        let data_start = offset + TAR_BLOCK_SIZE;
+        let data = field(image, data_start, size)?;
+
+        // Long names are split into a prefix and the name
+        let name = cstr_field(header, TAR_NAME, TAR_NAME_LEN)?;
+        let prefix = cstr_field(header, TAR_PREFIX, TAR_PREFIX_LEN)?;
+        let path = if prefix.is_empty() {
+            name
+        } else {
+            // Both fields are adjacent only in the header, so the path is
+            // rebuilt in the heap and kept for the lifetime of the kernel
+            let mut path = Vec::with_capacity(prefix.len() + 1 + name.len());
+            path.extend_from_slice(prefix);
+            path.push(b'/');
+            path.extend_from_slice(name);
+            path.leak()
+        };
+        match header[TAR_TYPE] {
+            TAR_TYPE_FILE | TAR_TYPE_FILE_OLD => add_file(files, path, FileType::File, data),
+            TAR_TYPE_DIRECTORY => add_file(files, path, FileType::Directory, &[]),
+            // Links, devices and the like have no meaning here
+            _ => {},
+        }
+        offset = data_start + (size + TAR_BLOCK_SIZE - 1) / TAR_BLOCK_SIZE * TAR_BLOCK_SIZE;
+    }
+    Ok(())
+}
+
+fn is_cpio(image: &[u8]) -> bool {
+    let magic = image.get(..CPIO_MAGIC.len());
+    magic == Some(CPIO_MAGIC) || magic == Some(CPIO_MAGIC_CRC)
+}
+
+fn cpio_field(header: &'static [u8], idx: usize) -> Result<usize, InitrdError> {
+    let start = CPIO_MAGIC.len() + idx * CPIO_FIELD_LEN;
+    parse_number(field(header, start, CPIO_FIELD_LEN)?, 16)
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn parse_cpio(image: &'static [u8], files: &mut Vec<File>) -> Result<(), InitrdError> {
+    let mut offset = 0;
+    loop {
+        let header = field(image, offset, CPIO_HEADER_SIZE)?;
+        if !is_cpio(header) {
+            return Err(InitrdError::BadHeader);
+        }
+        let mode = cpio_field(header, CPIO_MODE)? as u32;
+        let size = cpio_field(header, CPIO_FILESIZE)?;
+        let name_size = cpio_field(header, CPIO_NAMESIZE)?;
+        // The name size counts the terminating zero
+        let name = field(image, offset + CPIO_HEADER_SIZE, name_size)?;
+        let name = &name[..name_size.saturating_sub(1)];
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+        let data_start = align4(offset + CPIO_HEADER_SIZE + name_size);
+        let data = field(image, data_start, size)?;
+        match mode & CPIO_MODE_TYPE_MASK {
+            CPIO_MODE_FILE => add_file(files, name, FileType::File, data),
+            CPIO_MODE_DIRECTORY => add_file(files, name, FileType::Directory, &[]),
+            _ => {},
+        }
+        offset = align4(data_start + size);
+    }
+}
+
+pub fn init(image: &'static [u8]) -> Result<(), InitrdError> {
+    let mut files = Vec::new();
+    if is_cpio(image) {
+        parse_cpio(image, &mut files)?;
+    } else if is_tar(image) {
+        parse_tar(image, &mut files)?;
+    } else {
+        return Err(InitrdError::UnknownFormat);
+    }
+    *FILES.lock() = files.leak();
+    Ok(())
+}
+
+pub fn files() -> &'static [File] {
+    *FILES.lock()
+}
+
+// Directories only implied by the paths of their contents exist as well
+fn is_implied_directory(path: &str) -> bool {
+    path.is_empty() || files().iter().any(|file| {
+        file.path.len() > path.len() && file.path.starts_with(path)
+            && file.path.as_bytes()[path.len()] == b'/'
+    })
+}
+
+pub fn lookup(path: &str) -> Option<&'static File> {
+    let path = normalize(path);
+    // Archives may contain a path more than once, the last one wins
+    files().iter().rev().find(|file| file.path == path)
+}
+
+pub fn read(path: &str, offset: usize, buf: &mut [u8]) -> Option<usize> {
+    match lookup(path) {
+        Some(file) if file.file_type == FileType::File => Some(file.read(offset, buf)),
+        _ => None,
+    }
+}
+
+// Lists the direct children of a directory, None if it does not exist
+pub fn read_dir(path: &str) -> Option<Vec<DirEntry>> {
+    let path = normalize(path);
+    match lookup(path) {
+        Some(file) if file.file_type != FileType::Directory => return None,
+        None if !is_implied_directory(path) => return None,
+        _ => {},
+    }
+    let mut entries: Vec<DirEntry> = Vec::new();
+    for file in files() {
+        let rest = if path.is_empty() {
+            file.path
+        } else {
+            match file.path.strip_prefix(path).and_then(|rest| rest.strip_prefix('/')) {
+                Some(rest) => rest,
+                None => continue,
+            }
+        };
+        let (name, file_type) = match rest.split_once('/') {
+            Some((dir, _)) => (dir, FileType::Directory),
+            None => (rest, file.file_type),
+        };
+        match entries.iter_mut().find(|entry| entry.name == name) {
+            Some(entry) => {
+                if rest == name {
+                    entry.file_type = file_type;
+                }
+            },
+            None => entries.push(DirEntry { name, file_type }),
+        }
+    }
+    Some(entries)
+}
@@ -0,0 +1,20 @@
+use crate::ioport::Port;
+use crate::sync::InputBuffer;
+
+static PS2_DATA: Port = Port::new(0x60);
+
+static SCANCODES: InputBuffer<64> = InputBuffer::new();
+
+// IRQ 1, the controller sends nothing more until the scancode is read
+pub fn handle_interrupt() {
+    SCANCODES.push(PS2_DATA.in8());
+}
+
+// Raw set 1 scancodes, blocks the thread until a key event arrives
+pub fn read_scancode() -> u8 {
+    SCANCODES.pop()
+}
+
+pub fn try_read_scancode() -> Option<u8> {
+    SCANCODES.try_pop()
+}
@@ -1,12 +1,36 @@
 #![no_std]
+#![feature(alloc_error_handler)]
 #![feature(naked_functions)]
 #![feature(panic_info_message)]
 
+extern crate alloc;
+
+mod bootinfo;
+mod cmdline;
+mod cpu;
+mod elf;
 mod entry;
+mod heap;
 mod idt;
+mod initrd;
 mod ioport;
+mod keyboard;
+mod multiboot;
+mod multiboot2;
+mod pagefault;
+mod paging;
 mod panic;
 mod pic;
+mod pit;
+mod pmm;
+mod process;
+mod rtc;
 mod serial;
 mod sched;
+mod spinlock;
+mod sync;
+mod syscall;
+mod time;
+mod timer;
+mod usercopy;
 mod vga;
@@ -0,0 +1,738 @@
+use core::fmt::{Display, Debug, Formatter, Result, Write};
+use core::mem;
+
+pub const MULTIBOOT_BOOTLOADER_MAGIC: u32 = 0x2BADB002;
+
+const MULTIBOOT_INFO_MEMORY: u32 = 1 << 0;
+const MULTIBOOT_INFO_BOOT_DEVICE: u32 = 1 << 1;
+const MULTIBOOT_INFO_CMDLINE: u32 = 1 << 2;
+const MULTIBOOT_INFO_MODS: u32 = 1 << 3;
+const MULTIBOOT_INFO_AOUT_SYMS: u32 = 1 << 4;
+const MULTIBOOT_INFO_ELF_SHDR: u32 = 1 << 5;
+const MULTIBOOT_INFO_MEM_MAP: u32 = 1 << 6;
+const MULTIBOOT_INFO_DRIVE_INFO: u32 = 1 << 7;
+const MULTIBOOT_INFO_CONFIG_TABLE: u32 = 1 << 8;
+const MULTIBOOT_INFO_BOOT_LOADER_NAME: u32 = 1 << 9;
+const MULTIBOOT_INFO_APM_TABLE: u32 = 1 << 10;
+const MULTIBOOT_INFO_VBE_INFO: u32 = 1 << 11;
+const MULTIBOOT_INFO_FRAMEBUFFER_INFO: u32 = 1 << 12;
+
+#[repr(C)]
+pub struct MultibootInformation {
+    flags: u32,
+    mem_lower: u32,
+    mem_upper: u32,
+    boot_device: u32,
+    cmdline: u32,
+    mods_count: u32,
+    mods_addr: u32,
+    syms: [u32; 4],
+    mmap_length: u32,
+    mmap_addr: u32,
+    drives_length: u32,
+    drives_addr: u32,
+    config_table: u32,
+    boot_loader_name: u32,
+    apm_table: u32,
+    vbe_control_info: u32,
+    vbe_mode_info: u32,
+    vbe_mode: u16,
+    vbe_interface_seg: u16,
+    vbe_interface_off: u16,
+    vbe_interface_len: u16,
+    framebuffer_addr: u64,
+    framebuffer_pitch: u32,
+    framebuffer_width: u32,
+    framebuffer_height: u32,
+    framebuffer_bpp: u8,
+    framebuffer_type: u8,
+    color_info: [u8; 6],
+}
+const SA_MULTIBOOT_INFORMATION_SIZE: usize =
+    (mem::size_of::<MultibootInformation>() == 116) as usize - 1;
+
+// The structure handed over by the bootloader in ebx
+pub unsafe fn from_addr(addr: u32) -> &'static MultibootInformation {
+    &*(addr as *const MultibootInformation)
+}
+
+// Bootloader strings are not guaranteed to be UTF-8, other bytes are escaped
+pub struct Bytes<'a>(pub &'a [u8]);
+
+impl Display for Bytes<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for b in self.0 {
+            if b.is_ascii_graphic() || *b == b' ' {
+                f.write_char(*b as char)?;
+            } else {
+                write!(f, "\\x{:02X}", b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+unsafe fn slice_from_cstr(s: *const u8) -> &'static [u8] {
+    let mut count = 0usize;
+    loop {
+        if *s.add(count) == 0 {
+            break;
+        }
+        count += 1;
+    }
+    core::slice::from_raw_parts(s, count)
+}
+
+pub fn cstr(addr: u32) -> &'static [u8] {
+    unsafe { slice_from_cstr(addr as *const u8) }
+}
+
+pub fn bytes(addr: u32, len: u32) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) }
+}
+
+pub fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+pub fn read_u64(data: &[u8], offset: usize) -> u64 {
+    (read_u32(data, offset + 4) as u64) << 32 | read_u32(data, offset) as u64
+}
+
+pub struct BootDevice {
+    pub drive: u8,
+    // 0xFF for unused partition levels
+    pub partitions: [u8; 3],
+}
+
+#[repr(C)]
+pub struct Module {
+    mod_start: u32,
+    mod_end: u32,
+    cmdline: u32,
+    reserved: u32,
+}
+const SA_MODULE_SIZE: usize =
+    (mem::size_of::<Module>() == 16) as usize - 1;
+
+impl Module {
+    pub fn start(&self) -> u32 {
+        self.mod_start
+    }
+
+    pub fn end(&self) -> u32 {
+        self.mod_end
+    }
+
+    pub fn data(&self) -> &'static [u8] {
+        bytes(self.mod_start, self.mod_end.saturating_sub(self.mod_start))
+    }
+
+    pub fn cmdline(&self) -> &'static [u8] {
+        if self.cmdline == 0 {
+            return &[];
+        }
+        cstr(self.cmdline)
+    }
+
+    // Where the cmdline lies, to keep it out of the frame allocator
+    pub fn cmdline_range(&self) -> Option<(u32, u32)> {
+        match self.cmdline {
+            0 => None,
+            addr => Some((addr, addr + self.cmdline().len() as u32 + 1)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MemoryType {
+    Available,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Defective,
+    Other(u32),
+}
+
+impl MemoryType {
+    pub fn from_raw(mem_type: u32) -> MemoryType {
+        match mem_type {
+            1 => MemoryType::Available,
+            2 => MemoryType::Reserved,
+            3 => MemoryType::AcpiReclaimable,
+            4 => MemoryType::AcpiNvs,
+            5 => MemoryType::Defective,
+            other => MemoryType::Other(other),
+        }
+    }
+}
+
+impl Display for MemoryType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.write_str(match self {
+            MemoryType::Available => "available",
+            MemoryType::AcpiReclaimable => "ACPI",
+            MemoryType::AcpiNvs => "hibernation",
+            MemoryType::Defective => "defective",
+            MemoryType::Reserved | MemoryType::Other(_) => "reserved",
+        })
+    }
+}
+
+#[repr(C, packed)]
+struct MultibootMemory {
+    size: u32,
+    base_addr: u64,
+    length: u64,
+    mem_type: u32,
+}
+const SA_MULTIBOOT_MEMORY_SIZE: usize =
+    (mem::size_of::<MultibootMemory>() == 24) as usize - 1;
+
+#[derive(Copy, Clone)]
+pub struct MemoryRegion {
+    base_addr: u64,
+    length: u64,
+    mem_type: MemoryType,
+}
+
+impl MemoryRegion {
+    pub fn new(base_addr: u64, length: u64, mem_type: MemoryType) -> MemoryRegion {
+        MemoryRegion { base_addr, length, mem_type }
+    }
+
+    pub fn base_addr(&self) -> u64 {
+        self.base_addr
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn end_addr(&self) -> u64 {
+        self.base_addr + self.length
+    }
+
+    pub fn mem_type(&self) -> MemoryType {
+        self.mem_type
+    }
+}
+
+// Entries carry their own size, which does not count the size field itself
+pub struct MemoryMap {
+    data: &'static [u8],
+}
+
+impl Iterator for MemoryMap {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<MemoryRegion> {
+        if self.data.len() < mem::size_of::<MultibootMemory>() {
+            return None;
+        }
+        let entry = unsafe {
+            core::ptr::read_unaligned(self.data.as_ptr() as *const MultibootMemory)
+        };
+        let size = (entry.size as usize + 4).min(self.data.len());
+        self.data = &self.data[size..];
+        Some(MemoryRegion::new(entry.base_addr, entry.length, MemoryType::from_raw(entry.mem_type)))
+    }
+}
+
+pub struct AoutSymbols {
+    pub tab_size: u32,
+    pub str_size: u32,
+    pub addr: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ElfSectionHeader {
+    name: u32,
+    section_type: u32,
+    flags: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    info: u32,
+    addralign: u32,
+    entsize: u32,
+}
+const SA_ELF_SECTION_HEADER_SIZE: usize =
+    (mem::size_of::<ElfSectionHeader>() == 40) as usize - 1;
+
+const ELF_SHT_NULL: u32 = 0;
+
+impl ElfSectionHeader {
+    pub fn section_type(&self) -> u32 {
+        self.section_type
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn addr(&self) -> u32 {
+        self.addr
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+}
+
+pub struct ElfSection {
+    header: ElfSectionHeader,
+    name: &'static [u8],
+}
+
+impl ElfSection {
+    pub fn header(&self) -> &ElfSectionHeader {
+        &self.header
+    }
+
+    pub fn name(&self) -> &'static [u8] {
+        self.name
+    }
+}
+
+pub struct ElfSections {
+    headers: &'static [u8],
+    entry_size: usize,
+    // Section names are looked up in the loaded string table, if any
+    string_table: Option<&'static [u8]>,
+}
+
+impl ElfSections {
+    // None when the table does not fit into the address space
+    pub fn new(addr: u32, num: u32, entry_size: u32, string_table_idx: u32) -> Option<ElfSections> {
+        let size = num.checked_mul(entry_size)?;
+        addr.checked_add(size)?;
+        let mut sections = ElfSections {
+            headers: bytes(addr, size),
+            entry_size: entry_size as usize,
+            string_table: None,
+        };
+        sections.string_table = sections.header(string_table_idx as usize)
+            .filter(|header| header.section_type != ELF_SHT_NULL && header.addr != 0)
+            .map(|header| bytes(header.addr, header.size));
+        Some(sections)
+    }
+
+    fn header(&self, idx: usize) -> Option<ElfSectionHeader> {
+        let offset = idx.checked_mul(self.entry_size)?;
+        if self.entry_size < mem::size_of::<ElfSectionHeader>()
+            || offset + mem::size_of::<ElfSectionHeader>() > self.headers.len() {
+            return None;
+        }
+        Some(unsafe {
+            core::ptr::read_unaligned(self.headers[offset..].as_ptr() as *const ElfSectionHeader)
+        })
+    }
+}
+
+impl Iterator for ElfSections {
+    type Item = ElfSection;
+
+    fn next(&mut self) -> Option<ElfSection> {
+        let header = self.header(0)?;
+        self.headers = &self.headers[self.entry_size..];
+        let name = match self.string_table {
+            Some(table) if (header.name as usize) < table.len() => {
+                let name = &table[header.name as usize..];
+                &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())]
+            },
+            _ => &[],
+        };
+        Some(ElfSection { header, name })
+    }
+}
+
+pub struct Drive {
+    pub number: u8,
+    // 0 for CHS, 1 for LBA
+    pub mode: u8,
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+    ports: &'static [u8],
+}
+
+impl Drive {
+    pub fn ports(&self) -> impl Iterator<Item = u16> + 'static {
+        let ports = self.ports;
+        // The list is terminated by a zero port
+        (0..ports.len() / 2).map(move |i| read_u16(ports, i * 2)).take_while(|port| *port != 0)
+    }
+}
+
+const DRIVE_HEADER_SIZE: usize = 10;
+
+pub struct Drives {
+    data: &'static [u8],
+}
+
+impl Iterator for Drives {
+    type Item = Drive;
+
+    fn next(&mut self) -> Option<Drive> {
+        if self.data.len() < DRIVE_HEADER_SIZE {
+            return None;
+        }
+        let size = (read_u32(self.data, 0) as usize).clamp(DRIVE_HEADER_SIZE, self.data.len());
+        let entry = &self.data[..size];
+        self.data = &self.data[size..];
+        Some(Drive {
+            number: entry[4],
+            mode: entry[5],
+            cylinders: read_u16(entry, 6),
+            heads: entry[8],
+            sectors: entry[9],
+            ports: &entry[DRIVE_HEADER_SIZE..],
+        })
+    }
+}
+
+// The table returned by the BIOS call int 0x15, ah 0xC0
+pub struct ConfigTable {
+    pub model: u8,
+    pub submodel: u8,
+    pub bios_revision: u8,
+    pub features: [u8; 5],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ApmTable {
+    pub version: u16,
+    pub cseg: u16,
+    pub offset: u32,
+    pub cseg_16: u16,
+    pub dseg: u16,
+    pub flags: u16,
+    pub cseg_len: u16,
+    pub cseg_16_len: u16,
+    pub dseg_len: u16,
+}
+const SA_APM_TABLE_SIZE: usize =
+    (mem::size_of::<ApmTable>() == 20) as usize - 1;
+
+pub struct VbeControlInfo {
+    pub signature: [u8; 4],
+    pub version: u16,
+    pub capabilities: u32,
+    // In 64 KiB blocks
+    pub total_memory: u16,
+}
+
+pub struct VbeModeInfo {
+    pub attributes: u16,
+    pub pitch: u16,
+    pub width: u16,
+    pub height: u16,
+    pub bpp: u8,
+    pub memory_model: u8,
+    pub phys_base: u32,
+}
+
+pub struct Vbe {
+    pub mode: u16,
+    pub interface_seg: u16,
+    pub interface_off: u16,
+    pub interface_len: u16,
+    control_info: u32,
+    mode_info: u32,
+}
+
+const VBE_CONTROL_INFO_SIZE: u32 = 512;
+const VBE_MODE_INFO_SIZE: u32 = 256;
+
+impl Vbe {
+    pub fn control_info(&self) -> VbeControlInfo {
+        let data = bytes(self.control_info, VBE_CONTROL_INFO_SIZE);
+        VbeControlInfo {
+            signature: [data[0], data[1], data[2], data[3]],
+            version: read_u16(data, 4),
+            capabilities: read_u32(data, 10),
+            total_memory: read_u16(data, 18),
+        }
+    }
+
+    pub fn mode_info(&self) -> VbeModeInfo {
+        let data = bytes(self.mode_info, VBE_MODE_INFO_SIZE);
+        VbeModeInfo {
+            attributes: read_u16(data, 0),
+            pitch: read_u16(data, 16),
+            width: read_u16(data, 18),
+            height: read_u16(data, 20),
+            bpp: data[25],
+            memory_model: data[27],
+            phys_base: read_u32(data, 40),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct ColorPalette {
+    addr: u32,
+    num_colors: u16,
+}
+
+impl ColorPalette {
+    pub fn new(addr: u32, num_colors: u16) -> ColorPalette {
+        ColorPalette { addr, num_colors }
+    }
+
+    // Red, green and blue bytes of each color
+    pub fn colors(&self) -> impl Iterator<Item = (u8, u8, u8)> + 'static {
+        let data = bytes(self.addr, self.num_colors as u32 * 3);
+        data.chunks_exact(3).map(|c| (c[0], c[1], c[2]))
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum FramebufferType {
+    Indexed(ColorPalette),
+    // Position and size in bits of red, green and blue
+    Rgb {
+        red: (u8, u8),
+        green: (u8, u8),
+        blue: (u8, u8),
+    },
+    Text,
+    Unknown(u8),
+}
+
+#[derive(Copy, Clone)]
+pub struct Framebuffer {
+    pub addr: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    pub framebuffer_type: FramebufferType,
+}
+
+impl MultibootInformation {
+    fn has(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    // Lower and upper memory in KiB
+    pub fn memory_bounds(&self) -> Option<(u32, u32)> {
+        self.has(MULTIBOOT_INFO_MEMORY).then(|| (self.mem_lower, self.mem_upper))
+    }
+
+    pub fn boot_device(&self) -> Option<BootDevice> {
+        self.has(MULTIBOOT_INFO_BOOT_DEVICE).then(|| {
+            let [part3, part2, part1, drive] = self.boot_device.to_le_bytes();
+            BootDevice { drive, partitions: [part1, part2, part3] }
+        })
+    }
+
+    pub fn cmdline(&self) -> Option<&'static [u8]> {
+        (self.has(MULTIBOOT_INFO_CMDLINE) && self.cmdline != 0).then(|| cstr(self.cmdline))
+    }
+
+    pub fn cmdline_range(&self) -> Option<(u32, u32)> {
+        self.cmdline().map(|cmdline| (self.cmdline, self.cmdline + cmdline.len() as u32 + 1))
+    }
+
+    pub fn modules(&self) -> core::slice::Iter<'static, Module> {
+        let modules: &'static [Module] = if self.has(MULTIBOOT_INFO_MODS) && self.mods_addr != 0 {
+            unsafe { core::slice::from_raw_parts(
+                self.mods_addr as *const Module,
+                self.mods_count as usize) }
+        } else {
+            &[]
+        };
+        modules.iter()
+    }
+
+    // Where the module list itself lies
+    pub fn modules_range(&self) -> Option<(u32, u32)> {
+        self.has(MULTIBOOT_INFO_MODS).then(|| {
+            (self.mods_addr, self.mods_addr + self.mods_count * mem::size_of::<Module>() as u32)
+        })
+    }
+
+    pub fn aout_symbols(&self) -> Option<AoutSymbols> {
+        self.has(MULTIBOOT_INFO_AOUT_SYMS).then(|| AoutSymbols {
+            tab_size: self.syms[0],
+            str_size: self.syms[1],
+            addr: self.syms[2],
+        })
+    }
+
+    pub fn elf_sections(&self) -> Option<ElfSections> {
+        if !self.has(MULTIBOOT_INFO_ELF_SHDR) || self.has(MULTIBOOT_INFO_AOUT_SYMS) {
+            return None;
+        }
+        let [num, size, addr, shndx] = self.syms;
+        ElfSections::new(addr, num, size, shndx)
+    }
+
+    pub fn memory_map(&self) -> Option<MemoryMap> {
+        self.has(MULTIBOOT_INFO_MEM_MAP).then(|| MemoryMap {
+            data: bytes(self.mmap_addr, self.mmap_length),
+        })
+    }
+
+    pub fn memory_map_range(&self) -> Option<(u32, u32)> {
+        self.has(MULTIBOOT_INFO_MEM_MAP).then(|| (self.mmap_addr, self.mmap_addr + self.mmap_length))
+    }
+
+    pub fn drives(&self) -> Option<Drives> {
+        self.has(MULTIBOOT_INFO_DRIVE_INFO).then(|| Drives {
+            data: bytes(self.drives_addr, self.drives_length),
+        })
+    }
+
+    pub fn config_table(&self) -> Option<ConfigTable> {
+        if !self.has(MULTIBOOT_INFO_CONFIG_TABLE) || self.config_table == 0 {
+            return None;
+        }
+        // Starts with the number of bytes that follow
+        let len = read_u16(bytes(self.config_table, 2), 0) as u32;
+        let data = bytes(self.config_table + 2, len);
+        if data.len() < 8 {
+            return None;
+        }
+        Some(ConfigTable {
+            model: data[0],
+            submodel: data[1],
+            bios_revision: data[2],
+            features: [data[3], data[4], data[5], data[6], data[7]],
+        })
+    }
+
+    pub fn boot_loader_name(&self) -> Option<&'static [u8]> {
+        (self.has(MULTIBOOT_INFO_BOOT_LOADER_NAME) && self.boot_loader_name != 0)
+            .then(|| cstr(self.boot_loader_name))
+    }
+
+    pub fn apm_table(&self) -> Option<ApmTable> {
+        if !self.has(MULTIBOOT_INFO_APM_TABLE) || self.apm_table == 0 {
+            return None;
+        }
+        Some(unsafe { core::ptr::read_unaligned(self.apm_table as *const ApmTable) })
+    }
+
+    pub fn vbe(&self) -> Option<Vbe> {
+        self.has(MULTIBOOT_INFO_VBE_INFO).then(|| Vbe {
+            mode: self.vbe_mode,
+            interface_seg: self.vbe_interface_seg,
+            interface_off: self.vbe_interface_off,
+            interface_len: self.vbe_interface_len,
+            control_info: self.vbe_control_info,
+            mode_info: self.vbe_mode_info,
+        })
+    }
+
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        if !self.has(MULTIBOOT_INFO_FRAMEBUFFER_INFO) {
+            return None;
+        }
+        let c = self.color_info;
+        let framebuffer_type = match self.framebuffer_type {
+            0 => FramebufferType::Indexed(ColorPalette::new(
+                read_u32(&c, 0), read_u16(&c, 4))),
+            1 => FramebufferType::Rgb {
+                red: (c[0], c[1]),
+                green: (c[2], c[3]),
+                blue: (c[4], c[5]),
+            },
+            2 => FramebufferType::Text,
+            other => FramebufferType::Unknown(other),
+        };
+        Some(Framebuffer {
+            addr: self.framebuffer_addr,
+            pitch: self.framebuffer_pitch,
+            width: self.framebuffer_width,
+            height: self.framebuffer_height,
+            bpp: self.framebuffer_bpp,
+            framebuffer_type,
+        })
+    }
+}
+
+impl Debug for MultibootInformation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.write_str("MB:\n")?;
+        write!(f, "flags 0x{:08X}\n", self.flags)?;
+
+        if let Some((lower, upper)) = self.memory_bounds() {
+            write!(f, "range 0x{:08X} - 0x{:08X}\n", lower, upper)?;
+        }
+
+        if let Some(dev) = self.boot_device() {
+            write!(f, "boot device 0x{:02X} {:02X} {:02X} {:02X}\n",
+                   dev.drive, dev.partitions[0], dev.partitions[1], dev.partitions[2])?;
+        }
+
+        if let Some(cmdline) = self.cmdline() {
+            write!(f, "{}\n", Bytes(cmdline))?;
+        }
+
+        for module in self.modules() {
+            write!(f, "mod 0x{:08X} - 0x{:08X} {}\n",
+                   module.start(), module.end(), Bytes(module.cmdline()))?;
+        }
+
+        if let Some(syms) = self.aout_symbols() {
+            write!(f, "a.out syms 0x{:08X} tab {} str {}\n",
+                   syms.addr, syms.tab_size, syms.str_size)?;
+        }
+
+        if let Some(sections) = self.elf_sections() {
+            write!(f, "elf sections {}\n", sections.count())?;
+        }
+
+        if let Some(map) = self.memory_map() {
+            for mem in map {
+                write!(f, "mem 0x{:016X} - 0x{:016X} {}\n",
+                       mem.base_addr(), mem.end_addr(), mem.mem_type())?;
+            }
+        }
+
+        if let Some(drives) = self.drives() {
+            for drive in drives {
+                write!(f, "drive 0x{:02X} c {} h {} s {}\n",
+                       drive.number, drive.cylinders, drive.heads, drive.sectors)?;
+            }
+        }
+
+        if let Some(config) = self.config_table() {
+            write!(f, "bios model 0x{:02X} 0x{:02X} rev {}\n",
+                   config.model, config.submodel, config.bios_revision)?;
+        }
+
+        if let Some(name) = self.boot_loader_name() {
+            write!(f, "loader {}\n", Bytes(name))?;
+        }
+
+        if let Some(apm) = self.apm_table() {
+            write!(f, "apm {}.{}\n", apm.version >> 8, apm.version & 0xFF)?;
+        }
+
+        if let Some(vbe) = self.vbe() {
+            let mode = vbe.mode_info();
+            write!(f, "vbe mode 0x{:04X} {}x{}x{}\n", vbe.mode, mode.width, mode.height, mode.bpp)?;
+        }
+
+        if let Some(fb) = self.framebuffer() {
+            write!(f, "fb 0x{:016X} {}x{}x{}\n", fb.addr, fb.width, fb.height, fb.bpp)?;
+        }
+
+        Ok(())
+    }
+}
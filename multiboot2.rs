@@ -0,0 +1,387 @@
+use core::fmt::{Debug, Formatter, Result};
+use core::mem;
+use crate::multiboot::{self, ApmTable, Bytes, ColorPalette, ElfSections, Framebuffer,
+                       FramebufferType, MemoryRegion, MemoryType};
+
+pub const MULTIBOOT2_BOOTLOADER_MAGIC: u32 = 0x36D76289;
+
+const MB2_TAG_END: u32 = 0;
+const MB2_TAG_CMDLINE: u32 = 1;
+const MB2_TAG_BOOT_LOADER_NAME: u32 = 2;
+const MB2_TAG_MODULE: u32 = 3;
+const MB2_TAG_BASIC_MEMINFO: u32 = 4;
+const MB2_TAG_BOOTDEV: u32 = 5;
+const MB2_TAG_MMAP: u32 = 6;
+const MB2_TAG_FRAMEBUFFER: u32 = 8;
+const MB2_TAG_ELF_SECTIONS: u32 = 9;
+const MB2_TAG_APM: u32 = 10;
+const MB2_TAG_EFI32: u32 = 11;
+const MB2_TAG_EFI64: u32 = 12;
+const MB2_TAG_ACPI_OLD: u32 = 14;
+const MB2_TAG_ACPI_NEW: u32 = 15;
+const MB2_TAG_EFI_MMAP: u32 = 17;
+
+// Every tag starts with its type and size, and tags are 8 byte aligned
+const MB2_TAG_HEADER_SIZE: usize = 8;
+const MB2_TAG_ALIGN: usize = 8;
+const MB2_INFO_HEADER_SIZE: usize = 8;
+
+pub struct Tag {
+    tag_type: u32,
+    data: &'static [u8],
+}
+
+impl Tag {
+    pub fn tag_type(&self) -> u32 {
+        self.tag_type
+    }
+
+    // The payload without the type and size fields
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        self.data.get(offset..offset + 4).map(|_| multiboot::read_u32(self.data, offset))
+    }
+
+    fn u64_at(&self, offset: usize) -> Option<u64> {
+        self.data.get(offset..offset + 8).map(|_| multiboot::read_u64(self.data, offset))
+    }
+
+    // Strings are zero terminated within the tag
+    fn string_at(&self, offset: usize) -> &'static [u8] {
+        let s = self.data.get(offset..).unwrap_or(&[]);
+        &s[..s.iter().position(|b| *b == 0).unwrap_or(s.len())]
+    }
+}
+
+pub struct Tags {
+    data: &'static [u8],
+}
+
+impl Iterator for Tags {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        if self.data.len() < MB2_TAG_HEADER_SIZE {
+            return None;
+        }
+        let tag_type = multiboot::read_u32(self.data, 0);
+        let size = multiboot::read_u32(self.data, 4) as usize;
+        if tag_type == MB2_TAG_END || size < MB2_TAG_HEADER_SIZE || size > self.data.len() {
+            self.data = &[];
+            return None;
+        }
+        let tag = Tag {
+            tag_type,
+            data: &self.data[MB2_TAG_HEADER_SIZE..size],
+        };
+        let next = (size + MB2_TAG_ALIGN - 1) & !(MB2_TAG_ALIGN - 1);
+        self.data = self.data.get(next..).unwrap_or(&[]);
+        Some(tag)
+    }
+}
+
+pub struct Module {
+    start: u32,
+    end: u32,
+    cmdline: &'static [u8],
+}
+
+impl Module {
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    pub fn cmdline(&self) -> &'static [u8] {
+        self.cmdline
+    }
+}
+
+pub struct Modules {
+    tags: Tags,
+}
+
+impl Iterator for Modules {
+    type Item = Module;
+
+    fn next(&mut self) -> Option<Module> {
+        let tag = self.tags.find(|tag| tag.tag_type == MB2_TAG_MODULE)?;
+        Some(Module {
+            start: tag.u32_at(0)?,
+            end: tag.u32_at(4)?,
+            cmdline: tag.string_at(8),
+        })
+    }
+}
+
+const MB2_MMAP_HEADER_SIZE: usize = 8;
+const MB2_MMAP_ENTRY_SIZE: usize = 24;
+
+pub struct MemoryMap {
+    data: &'static [u8],
+    entry_size: usize,
+}
+
+impl Iterator for MemoryMap {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<MemoryRegion> {
+        if self.entry_size < MB2_MMAP_ENTRY_SIZE || self.data.len() < self.entry_size {
+            return None;
+        }
+        let entry = &self.data[..self.entry_size];
+        self.data = &self.data[self.entry_size..];
+        // Same layout and type numbering as the v1 memory map without the size field
+        Some(MemoryRegion::new(multiboot::read_u64(entry, 0), multiboot::read_u64(entry, 8),
+                               MemoryType::from_raw(multiboot::read_u32(entry, 16))))
+    }
+}
+
+pub struct EfiMemoryDescriptor {
+    pub mem_type: u32,
+    pub phys_start: u64,
+    pub virt_start: u64,
+    pub num_pages: u64,
+    pub attribute: u64,
+}
+
+const EFI_MEMORY_DESCRIPTOR_SIZE: usize = 40;
+
+pub struct EfiMemoryMap {
+    data: &'static [u8],
+    entry_size: usize,
+}
+
+impl Iterator for EfiMemoryMap {
+    type Item = EfiMemoryDescriptor;
+
+    fn next(&mut self) -> Option<EfiMemoryDescriptor> {
+        if self.entry_size < EFI_MEMORY_DESCRIPTOR_SIZE || self.data.len() < self.entry_size {
+            return None;
+        }
+        let entry = &self.data[..self.entry_size];
+        self.data = &self.data[self.entry_size..];
+        Some(EfiMemoryDescriptor {
+            mem_type: multiboot::read_u32(entry, 0),
+            phys_start: multiboot::read_u64(entry, 8),
+            virt_start: multiboot::read_u64(entry, 16),
+            num_pages: multiboot::read_u64(entry, 24),
+            attribute: multiboot::read_u64(entry, 32),
+        })
+    }
+}
+
+pub struct BootDevice {
+    pub bios_device: u32,
+    pub partition: u32,
+    pub sub_partition: u32,
+}
+
+pub enum EfiSystemTable {
+    Efi32(u32),
+    Efi64(u64),
+}
+
+pub struct Multiboot2Information {
+    data: &'static [u8],
+}
+
+impl Multiboot2Information {
+    // The structure starts with its total size, the tags follow a reserved field
+    pub unsafe fn from_addr(addr: u32) -> Multiboot2Information {
+        let total_size = *(addr as *const u32);
+        Multiboot2Information {
+            data: multiboot::bytes(addr, total_size),
+        }
+    }
+
+    pub fn addr(&self) -> u32 {
+        self.data.as_ptr() as u32
+    }
+
+    pub fn total_size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn tags(&self) -> Tags {
+        Tags {
+            data: self.data.get(MB2_INFO_HEADER_SIZE..).unwrap_or(&[]),
+        }
+    }
+
+    fn tag(&self, tag_type: u32) -> Option<Tag> {
+        self.tags().find(|tag| tag.tag_type == tag_type)
+    }
+
+    pub fn cmdline(&self) -> Option<&'static [u8]> {
+        self.tag(MB2_TAG_CMDLINE).map(|tag| tag.string_at(0))
+    }
+
+    pub fn boot_loader_name(&self) -> Option<&'static [u8]> {
+        self.tag(MB2_TAG_BOOT_LOADER_NAME).map(|tag| tag.string_at(0))
+    }
+
+    // Lower and upper memory in KiB
+    pub fn memory_bounds(&self) -> Option<(u32, u32)> {
+        let tag = self.tag(MB2_TAG_BASIC_MEMINFO)?;
+        Some((tag.u32_at(0)?, tag.u32_at(4)?))
+    }
+
+    pub fn boot_device(&self) -> Option<BootDevice> {
+        let tag = self.tag(MB2_TAG_BOOTDEV)?;
+        Some(BootDevice {
+            bios_device: tag.u32_at(0)?,
+            partition: tag.u32_at(4)?,
+            sub_partition: tag.u32_at(8)?,
+        })
+    }
+
+    pub fn modules(&self) -> Modules {
+        Modules { tags: self.tags() }
+    }
+
+    pub fn memory_map(&self) -> Option<MemoryMap> {
+        let tag = self.tag(MB2_TAG_MMAP)?;
+        Some(MemoryMap {
+            entry_size: tag.u32_at(0)? as usize,
+            data: tag.data.get(MB2_MMAP_HEADER_SIZE..)?,
+        })
+    }
+
+    pub fn efi_memory_map(&self) -> Option<EfiMemoryMap> {
+        let tag = self.tag(MB2_TAG_EFI_MMAP)?;
+        Some(EfiMemoryMap {
+            entry_size: tag.u32_at(0)? as usize,
+            data: tag.data.get(MB2_MMAP_HEADER_SIZE..)?,
+        })
+    }
+
+    pub fn efi_system_table(&self) -> Option<EfiSystemTable> {
+        if let Some(tag) = self.tag(MB2_TAG_EFI64) {
+            return tag.u64_at(0).map(EfiSystemTable::Efi64);
+        }
+        self.tag(MB2_TAG_EFI32).and_then(|tag| tag.u32_at(0)).map(EfiSystemTable::Efi32)
+    }
+
+    // A copy of the ACPI RSDP, the newer revision is preferred
+    pub fn rsdp(&self) -> Option<&'static [u8]> {
+        self.tag(MB2_TAG_ACPI_NEW).or_else(|| self.tag(MB2_TAG_ACPI_OLD)).map(|tag| tag.data)
+    }
+
+    pub fn elf_sections(&self) -> Option<ElfSections> {
+        let tag = self.tag(MB2_TAG_ELF_SECTIONS)?;
+        let (num, entry_size, string_table_idx) = (tag.u32_at(0)?, tag.u32_at(4)?, tag.u32_at(8)?);
+        // The headers are part of the tag
+        let headers = tag.data.get(12..)?;
+        if (num as usize).checked_mul(entry_size as usize)? > headers.len() {
+            return None;
+        }
+        ElfSections::new(headers.as_ptr() as u32, num, entry_size, string_table_idx)
+    }
+
+    pub fn apm_table(&self) -> Option<ApmTable> {
+        let tag = self.tag(MB2_TAG_APM)?;
+        if tag.data.len() < mem::size_of::<ApmTable>() {
+            return None;
+        }
+        Some(unsafe { core::ptr::read_unaligned(tag.data.as_ptr() as *const ApmTable) })
+    }
+
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        let tag = self.tag(MB2_TAG_FRAMEBUFFER)?;
+        let data = tag.data;
+        if data.len() < 24 {
+            return None;
+        }
+        let framebuffer_type = match data[21] {
+            // The palette follows the number of colors inside the tag
+            0 => {
+                let num_colors = multiboot::read_u16(data.get(24..26)?, 0);
+                let palette = data.get(26..26 + num_colors as usize * 3)?;
+                FramebufferType::Indexed(ColorPalette::new(palette.as_ptr() as u32, num_colors))
+            },
+            1 => {
+                let c = data.get(24..30)?;
+                FramebufferType::Rgb {
+                    red: (c[0], c[1]),
+                    green: (c[2], c[3]),
+                    blue: (c[4], c[5]),
+                }
+            },
+            2 => FramebufferType::Text,
+            other => FramebufferType::Unknown(other),
+        };
+        Some(Framebuffer {
+            addr: tag.u64_at(0)?,
+            pitch: tag.u32_at(8)?,
+            width: tag.u32_at(12)?,
+            height: tag.u32_at(16)?,
+            bpp: data[20],
+            framebuffer_type,
+        })
+    }
+}
+
+impl Debug for Multiboot2Information {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.write_str("MB2:\n")?;
+        write!(f, "size 0x{:08X}\n", self.total_size())?;
+
+        if let Some((lower, upper)) = self.memory_bounds() {
+            write!(f, "range 0x{:08X} - 0x{:08X}\n", lower, upper)?;
+        }
+
+        if let Some(dev) = self.boot_device() {
+            write!(f, "boot device 0x{:02X} {} {}\n",
+                   dev.bios_device, dev.partition, dev.sub_partition)?;
+        }
+
+        if let Some(cmdline) = self.cmdline() {
+            write!(f, "{}\n", Bytes(cmdline))?;
+        }
+
+        for module in self.modules() {
+            write!(f, "mod 0x{:08X} - 0x{:08X} {}\n",
+                   module.start(), module.end(), Bytes(module.cmdline()))?;
+        }
+
+        if let Some(sections) = self.elf_sections() {
+            write!(f, "elf sections {}\n", sections.count())?;
+        }
+
+        if let Some(map) = self.memory_map() {
+            for mem in map {
+                write!(f, "mem 0x{:016X} - 0x{:016X} {}\n",
+                       mem.base_addr(), mem.end_addr(), mem.mem_type())?;
+            }
+        }
+
+        if let Some(map) = self.efi_memory_map() {
+            write!(f, "efi mmap {} entries\n", map.count())?;
+        }
+
+        if let Some(name) = self.boot_loader_name() {
+            write!(f, "loader {}\n", Bytes(name))?;
+        }
+
+        if let Some(apm) = self.apm_table() {
+            write!(f, "apm {}.{}\n", apm.version >> 8, apm.version & 0xFF)?;
+        }
+
+        if let Some(rsdp) = self.rsdp() {
+            write!(f, "rsdp 0x{:08X}\n", rsdp.as_ptr() as u32)?;
+        }
+
+        if let Some(fb) = self.framebuffer() {
+            write!(f, "fb 0x{:016X} {}x{}x{}\n", fb.addr, fb.width, fb.height, fb.bpp)?;
+        }
+
+        Ok(())
+    }
+}
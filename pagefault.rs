@@ -0,0 +1,113 @@
+use crate::cpu;
+use crate::idt::{X86_INT_STATE_CS, X86_INT_STATE_EIP, X86_INT_STATE_ESP};
+use crate::paging;
+use crate::sched::{self, Registers};
+use crate::serial::Serial;
+use crate::usercopy;
+use core::fmt::{Display, Formatter, Result, Write};
+
+const X86_PF_PRESENT: u32 = 1 << 0;
+const X86_PF_WRITE: u32 = 1 << 1;
+const X86_PF_USER: u32 = 1 << 2;
+const X86_PF_RESERVED: u32 = 1 << 3;
+const X86_PF_INSTRUCTION: u32 = 1 << 4;
+
+// push and pusha may touch memory slightly below esp
+const STACK_GROWTH_SLACK: u32 = 32;
+
+// Exit code of a user thread killed by an unresolvable fault, as a shell would report SIGSEGV
+pub const SEGFAULT_EXIT_CODE: u32 = 128 + 11;
+
+struct PageFault {
+    addr: u32,
+    err: u32,
+    eip: u32,
+    cs: u32,
+}
+
+impl Display for PageFault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "page fault at 0x{:08X}, eip 0x{:08X}, cs 0x{:02X}, error 0x{:X}\n",
+               self.addr, self.eip, self.cs, self.err)?;
+        f.write_str(if self.err & X86_PF_PRESENT != 0 { "protection violation" } else { "page not present" })?;
+        f.write_str(if self.err & X86_PF_WRITE != 0 { ", write" } else { ", read" })?;
+        f.write_str(if self.err & X86_PF_USER != 0 { ", user mode" } else { ", kernel mode" })?;
+        if self.err & X86_PF_RESERVED != 0 {
+            f.write_str(", reserved bit set")?;
+        }
+        if self.err & X86_PF_INSTRUCTION != 0 {
+            f.write_str(", instruction fetch")?;
+        }
+        write!(f, "\npte 0x{:08X}\n", paging::page_entry(self.addr))
+    }
+}
+
+// Puts a demand page at the address if it lies in the growable part of a
+// user stack, the next access faults it in
+pub fn extend_user_stack(addr: u32) -> bool {
+    let page = addr & !(paging::PAGE_SIZE - 1);
+    sched::is_user_stack_area(addr)
+        && paging::map_demand(page, paging::X86_PTE_WRITABLE | paging::X86_PTE_USER).is_ok()
+}
+
+fn grow_user_stack(addr: u32, esp: u32) -> bool {
+    if addr.saturating_add(STACK_GROWTH_SLACK) < esp {
+        return false;
+    }
+    extend_user_stack(addr) && paging::resolve_demand(addr & !(paging::PAGE_SIZE - 1))
+}
+
+fn resolve(fault: &PageFault, user_esp: u32) -> bool {
+    if fault.err & X86_PF_RESERVED != 0 {
+        return false;
+    }
+    if fault.err & X86_PF_PRESENT == 0 {
+        if paging::resolve_demand(fault.addr) {
+            return true;
+        }
+        return fault.err & X86_PF_USER != 0 && grow_user_stack(fault.addr, user_esp);
+    }
+    fault.err & X86_PF_WRITE != 0 && paging::resolve_cow(fault.addr)
+}
+
+pub fn handle_page_fault(int_state: *mut u32, err: u32) {
+    let fault = unsafe {
+        PageFault {
+            addr: cpu::read_cr2(),
+            err,
+            eip: *int_state.offset(X86_INT_STATE_EIP as isize),
+            cs: *int_state.offset(X86_INT_STATE_CS as isize),
+        }
+    };
+    let from_user = fault.cs & 0b11 == 0b11;
+    let user_esp = if from_user {
+        unsafe { *int_state.offset(X86_INT_STATE_ESP as isize) }
+    } else {
+        0
+    };
+
+    if resolve(&fault, user_esp) {
+        return;
+    }
+
+    if from_user {
+        let id = sched::current_id().unwrap_or(0);
+        write!(Serial, "killing thread {}\n{}{}", id, fault, Registers::from_int_state(int_state)).unwrap();
+        sched::thread_exit(SEGFAULT_EXIT_CODE);
+    }
+
+    // Kernel code touching user memory continues at its fixup
+    if let Some(fixup) = usercopy::search_exception_table(fault.eip) {
+        unsafe {
+            *int_state.offset(X86_INT_STATE_EIP as isize) = fixup;
+        }
+        return;
+    }
+
+    panic!("\
+        kernel oops\n\
+        {}\
+        thread:\n\
+        {}",
+        fault, Registers::from_int_state(int_state));
+}
@@ -0,0 +1,398 @@
+use crate::cpu;
+use crate::pmm;
+use crate::spinlock::IrqSpinLock;
+use core::ptr::{addr_of, addr_of_mut};
+
+pub const PAGE_SIZE: u32 = 4096;
+
+pub const X86_PTE_PRESENT: u32 = 1 << 0;
+pub const X86_PTE_WRITABLE: u32 = 1 << 1;
+pub const X86_PTE_USER: u32 = 1 << 2;
+pub const X86_PTE_WRITE_THROUGH: u32 = 1 << 3;
+pub const X86_PTE_CACHE_DISABLE: u32 = 1 << 4;
+pub const X86_PTE_ACCESSED: u32 = 1 << 5;
+pub const X86_PTE_DIRTY: u32 = 1 << 6;
+pub const X86_PDE_HUGE: u32 = 1 << 7;
+pub const X86_PTE_GLOBAL: u32 = 1 << 8;
+// Bits 9-11 are free for the kernel: a zeroed frame is allocated on first
+// access to a demand entry, a copy is made on first write to a COW entry
+pub const X86_PTE_DEMAND: u32 = 1 << 9;
+pub const X86_PTE_COW: u32 = 1 << 10;
+
+const X86_PTE_FLAGS_MASK: u32 = 0xFFF;
+const X86_PTE_ADDR_MASK: u32 = !X86_PTE_FLAGS_MASK;
+const X86_PDE_HUGE_ADDR_MASK: u32 = 0xFFC0_0000;
+
+// Physical memory below this address is identity-mapped into every address space
+pub const IDENTITY_MAP_END: u32 = 0x4000_0000;
+pub const USER_START: u32 = 0x4000_0000;
+pub const USER_END: u32 = 0xC000_0000;
+pub const KERNEL_HIGH_START: u32 = 0xC000_0000;
+
+const ENTRIES: usize = 1024;
+const LARGE_PAGE_SIZE: u32 = PAGE_SIZE * ENTRIES as u32;
+
+#[repr(C, align(4096))]
+struct PageTable {
+    entries: [u32; ENTRIES],
+}
+
+static mut KERNEL_PAGE_DIRECTORY: PageTable = PageTable { entries: [0; ENTRIES] };
+// First 4 MiB are mapped with small pages to leave the null page out
+static mut LOW_PAGE_TABLE: PageTable = PageTable { entries: [0; ENTRIES] };
+// Held while entries are changed, the kernel tables are shared by every address space
+static PAGE_TABLES: IrqSpinLock<()> = IrqSpinLock::new(());
+
+#[derive(Debug)]
+pub enum MapError {
+    OutOfMemory,
+    AlreadyMapped,
+    HugePage,
+}
+
+fn pd_idx(virt: u32) -> usize {
+    (virt >> 22) as usize
+}
+
+fn pt_idx(virt: u32) -> usize {
+    ((virt >> 12) & 0x3FF) as usize
+}
+
+fn page_directory() -> *mut u32 {
+    (cpu::read_cr3() & X86_PTE_ADDR_MASK) as *mut u32
+}
+
+pub fn kernel_page_directory() -> u32 {
+    unsafe { addr_of!(KERNEL_PAGE_DIRECTORY) as u32 }
+}
+
+pub fn switch_page_directory(pd: u32) {
+    if cpu::read_cr3() & X86_PTE_ADDR_MASK != pd {
+        cpu::write_cr3(pd);
+    }
+}
+
+// Stale translations only matter for the page directory that is loaded
+fn flush(pd: *mut u32, virt: u32) {
+    if pd == page_directory() {
+        cpu::invlpg(virt);
+    }
+}
+
+pub fn alloc_zeroed_frame() -> Option<u32> {
+    let frame = pmm::alloc_frame()?;
+    unsafe {
+        core::ptr::write_bytes(frame as *mut u32, 0, ENTRIES);
+    }
+    Some(frame)
+}
+
+unsafe fn page_table(pd: *mut u32, virt: u32, create: bool) -> Result<*mut u32, MapError> {
+    let pde = pd.add(pd_idx(virt));
+    if *pde & X86_PTE_PRESENT == 0 {
+        if !create {
+            return Ok(core::ptr::null_mut());
+        }
+        let table = alloc_zeroed_frame().ok_or(MapError::OutOfMemory)?;
+        let mut flags = X86_PTE_PRESENT | X86_PTE_WRITABLE;
+        if virt >= USER_START && virt < USER_END {
+            flags |= X86_PTE_USER;
+        }
+        *pde = table | flags;
+    } else if *pde & X86_PDE_HUGE != 0 {
+        return Err(MapError::HugePage);
+    }
+    Ok((*pde & X86_PTE_ADDR_MASK) as *mut u32)
+}
+
+unsafe fn page_entry_ptr(pd: *mut u32, virt: u32, create: bool) -> Result<*mut u32, MapError> {
+    let pt = page_table(pd, virt, create)?;
+    if pt.is_null() {
+        return Ok(pt);
+    }
+    Ok(pt.add(pt_idx(virt)))
+}
+
+unsafe fn set_page_entry(pd: *mut u32, virt: u32, entry: u32) -> Result<(), MapError> {
+    let _tables = PAGE_TABLES.lock();
+    let pte = page_entry_ptr(pd, virt, true)?;
+    if *pte & (X86_PTE_PRESENT | X86_PTE_DEMAND) != 0 {
+        return Err(MapError::AlreadyMapped);
+    }
+    *pte = entry;
+    flush(pd, virt);
+    Ok(())
+}
+
+unsafe fn map_page_in(pd: *mut u32, virt: u32, phys: u32, flags: u32) -> Result<(), MapError> {
+    let flags = flags & X86_PTE_FLAGS_MASK & !(X86_PTE_DEMAND | X86_PTE_COW);
+    set_page_entry(pd, virt, (phys & X86_PTE_ADDR_MASK) | flags | X86_PTE_PRESENT)
+}
+
+unsafe fn map_demand_in(pd: *mut u32, virt: u32, flags: u32) -> Result<(), MapError> {
+    let flags = flags & X86_PTE_FLAGS_MASK & !(X86_PTE_PRESENT | X86_PTE_COW);
+    set_page_entry(pd, virt, flags | X86_PTE_DEMAND)
+}
+
+unsafe fn map_cow_in(pd: *mut u32, virt: u32, phys: u32, flags: u32) -> Result<(), MapError> {
+    let flags = flags & X86_PTE_FLAGS_MASK & !(X86_PTE_WRITABLE | X86_PTE_DEMAND);
+    set_page_entry(pd, virt, (phys & X86_PTE_ADDR_MASK) | flags | X86_PTE_COW | X86_PTE_PRESENT)
+}
+
+unsafe fn unmap_page_in(pd: *mut u32, virt: u32) -> Option<u32> {
+    let _tables = PAGE_TABLES.lock();
+    let pte = page_entry_ptr(pd, virt, false).ok()?;
+    if pte.is_null() {
+        return None;
+    }
+    let entry = *pte;
+    *pte = 0;
+    if entry & X86_PTE_PRESENT == 0 {
+        return None;
+    }
+    flush(pd, virt);
+    if entry & X86_PTE_COW != 0 {
+        return None;
+    }
+    Some(entry & X86_PTE_ADDR_MASK)
+}
+
+unsafe fn page_entry_in(pd: *mut u32, virt: u32) -> u32 {
+    let pde = *pd.add(pd_idx(virt));
+    if pde & X86_PTE_PRESENT == 0 || pde & X86_PDE_HUGE != 0 {
+        return pde;
+    }
+    let pt = (pde & X86_PTE_ADDR_MASK) as *const u32;
+    *pt.add(pt_idx(virt))
+}
+
+unsafe fn translate_in(pd: *mut u32, virt: u32) -> Option<u32> {
+    let pde = *pd.add(pd_idx(virt));
+    if pde & X86_PTE_PRESENT == 0 {
+        return None;
+    }
+    if pde & X86_PDE_HUGE != 0 {
+        return Some((pde & X86_PDE_HUGE_ADDR_MASK) | (virt & !X86_PDE_HUGE_ADDR_MASK));
+    }
+    let pte = page_entry_in(pd, virt);
+    if pte & X86_PTE_PRESENT == 0 {
+        return None;
+    }
+    Some((pte & X86_PTE_ADDR_MASK) | (virt & X86_PTE_FLAGS_MASK))
+}
+
+pub fn map_page(virt: u32, phys: u32, flags: u32) -> Result<(), MapError> {
+    unsafe { map_page_in(page_directory(), virt, phys, flags) }
+}
+
+// The frame is allocated by the page fault handler on first access
+pub fn map_demand(virt: u32, flags: u32) -> Result<(), MapError> {
+    unsafe { map_demand_in(page_directory(), virt, flags) }
+}
+
+// Maps a frame owned by someone else read-only, the first write gets a private copy
+pub fn map_cow(virt: u32, phys: u32, flags: u32) -> Result<(), MapError> {
+    unsafe { map_cow_in(page_directory(), virt, phys, flags) }
+}
+
+// Returns the frame owned by the mapping, frames behind COW entries are not
+pub fn unmap_page(virt: u32) -> Option<u32> {
+    unsafe { unmap_page_in(page_directory(), virt) }
+}
+
+// Raw entry for the page, including entries that are not present
+pub fn page_entry(virt: u32) -> u32 {
+    unsafe { page_entry_in(page_directory(), virt) }
+}
+
+pub fn translate(virt: u32) -> Option<u32> {
+    unsafe { translate_in(page_directory(), virt) }
+}
+
+pub fn page_flags(virt: u32) -> Option<u32> {
+    let entry = page_entry(virt);
+    if entry & X86_PTE_PRESENT == 0 {
+        return None;
+    }
+    Some(entry & X86_PTE_FLAGS_MASK)
+}
+
+// Both only run from the page fault handler, where a held lock means the
+// faulting code holds it and waiting would never end
+pub fn resolve_demand(virt: u32) -> bool {
+    let _tables = match PAGE_TABLES.try_lock() {
+        Some(tables) => tables,
+        None => return false,
+    };
+    unsafe {
+        let pte = match page_entry_ptr(page_directory(), virt, false) {
+            Ok(pte) if !pte.is_null() => pte,
+            _ => return false,
+        };
+        if *pte & X86_PTE_DEMAND == 0 || *pte & X86_PTE_PRESENT != 0 {
+            return false;
+        }
+        let frame = match alloc_zeroed_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        *pte = frame | (*pte & X86_PTE_FLAGS_MASK & !X86_PTE_DEMAND) | X86_PTE_PRESENT;
+        cpu::invlpg(virt);
+        true
+    }
+}
+
+pub fn resolve_cow(virt: u32) -> bool {
+    let _tables = match PAGE_TABLES.try_lock() {
+        Some(tables) => tables,
+        None => return false,
+    };
+    unsafe {
+        let pte = match page_entry_ptr(page_directory(), virt, false) {
+            Ok(pte) if !pte.is_null() => pte,
+            _ => return false,
+        };
+        if *pte & X86_PTE_COW == 0 || *pte & X86_PTE_PRESENT == 0 {
+            return false;
+        }
+        let frame = match pmm::alloc_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let src = (*pte & X86_PTE_ADDR_MASK) as *const u8;
+        core::ptr::copy_nonoverlapping(src, frame as *mut u8, PAGE_SIZE as usize);
+        *pte = frame | (*pte & X86_PTE_FLAGS_MASK & !X86_PTE_COW) | X86_PTE_WRITABLE;
+        cpu::invlpg(virt);
+        true
+    }
+}
+
+// A page directory of its own for the user range, the kernel ranges are
+// shared with the kernel page directory
+pub struct AddressSpace {
+    page_directory: u32,
+}
+
+impl AddressSpace {
+    pub fn new() -> Option<AddressSpace> {
+        let pd = alloc_zeroed_frame()?;
+        unsafe {
+            let kernel_pd = addr_of!(KERNEL_PAGE_DIRECTORY.entries) as *const u32;
+            let new_pd = pd as *mut u32;
+            for i in (0..pd_idx(USER_START)).chain(pd_idx(KERNEL_HIGH_START)..ENTRIES) {
+                *new_pd.add(i) = *kernel_pd.add(i);
+            }
+        }
+        Some(AddressSpace { page_directory: pd })
+    }
+
+    pub fn page_directory(&self) -> u32 {
+        self.page_directory
+    }
+
+    fn pd(&self) -> *mut u32 {
+        self.page_directory as *mut u32
+    }
+
+    pub fn map_page(&self, virt: u32, phys: u32, flags: u32) -> Result<(), MapError> {
+        unsafe { map_page_in(self.pd(), virt, phys, flags) }
+    }
+
+    pub fn map_demand(&self, virt: u32, flags: u32) -> Result<(), MapError> {
+        unsafe { map_demand_in(self.pd(), virt, flags) }
+    }
+
+    pub fn map_cow(&self, virt: u32, phys: u32, flags: u32) -> Result<(), MapError> {
+        unsafe { map_cow_in(self.pd(), virt, phys, flags) }
+    }
+
+    pub fn unmap_page(&self, virt: u32) -> Option<u32> {
+        unsafe { unmap_page_in(self.pd(), virt) }
+    }
+
+    pub fn translate(&self, virt: u32) -> Option<u32> {
+        unsafe { translate_in(self.pd(), virt) }
+    }
+
+    // Copies through the identity map, so the space need not be active
+    pub fn write(&self, virt: u32, data: &[u8]) -> bool {
+        let mut done = 0;
+        while done < data.len() {
+            let addr = virt + done as u32;
+            let phys = match self.translate(addr) {
+                Some(phys) => phys,
+                None => return false,
+            };
+            let size = ((PAGE_SIZE - addr % PAGE_SIZE) as usize).min(data.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[done..].as_ptr(), phys as *mut u8, size);
+            }
+            done += size;
+        }
+        true
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if cpu::read_cr3() & X86_PTE_ADDR_MASK == self.page_directory {
+            panic!("dropping the active address space");
+        }
+        unsafe {
+            let pd = self.pd();
+            for i in pd_idx(USER_START)..pd_idx(USER_END) {
+                let pde = *pd.add(i);
+                if pde & X86_PTE_PRESENT == 0 {
+                    continue;
+                }
+                let pt = (pde & X86_PTE_ADDR_MASK) as *const u32;
+                for j in 0..ENTRIES {
+                    let pte = *pt.add(j);
+                    if pte & X86_PTE_PRESENT != 0 && pte & X86_PTE_COW == 0 {
+                        pmm::free_frame(pte & X86_PTE_ADDR_MASK);
+                    }
+                }
+                pmm::free_frame(pde & X86_PTE_ADDR_MASK);
+            }
+        }
+        pmm::free_frame(self.page_directory);
+    }
+}
+
+pub fn init() {
+    // The identity mapping is built from 4 MiB global pages
+    let (_, _, _, features) = cpu::cpuid(1);
+    if features & cpu::CPUID_1_EDX_PSE == 0 {
+        panic!("CPU lacks 4 MiB pages (PSE)");
+    }
+    if features & cpu::CPUID_1_EDX_PGE == 0 {
+        panic!("CPU lacks global pages (PGE)");
+    }
+    unsafe {
+        let pd = addr_of_mut!(KERNEL_PAGE_DIRECTORY.entries) as *mut u32;
+        let low_pt = addr_of_mut!(LOW_PAGE_TABLE.entries) as *mut u32;
+
+        // Page 0 stays unmapped so null dereferences fault
+        for i in 1..ENTRIES {
+            let addr = i as u32 * PAGE_SIZE;
+            *low_pt.add(i) = addr | X86_PTE_PRESENT | X86_PTE_WRITABLE | X86_PTE_GLOBAL;
+        }
+        *pd = low_pt as u32 | X86_PTE_PRESENT | X86_PTE_WRITABLE;
+
+        for i in 1..pd_idx(IDENTITY_MAP_END) {
+            let addr = i as u32 * LARGE_PAGE_SIZE;
+            *pd.add(i) = addr | X86_PTE_PRESENT | X86_PTE_WRITABLE | X86_PDE_HUGE | X86_PTE_GLOBAL;
+        }
+
+        // Tables for the upper kernel region are created up front so that
+        // every address space can share them
+        for i in pd_idx(KERNEL_HIGH_START)..ENTRIES {
+            let table = alloc_zeroed_frame().expect("no memory for kernel page tables");
+            *pd.add(i) = table | X86_PTE_PRESENT | X86_PTE_WRITABLE;
+        }
+
+        cpu::write_cr4(cpu::read_cr4() | cpu::X86_CR4_PSE | cpu::X86_CR4_PGE);
+        cpu::write_cr3(pd as u32);
+        cpu::write_cr0(cpu::read_cr0() | cpu::X86_CR0_PG | cpu::X86_CR0_WP);
+    }
+}
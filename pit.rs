@@ -0,0 +1,64 @@
+use crate::ioport::Port;
+
+// Input clock of all three channels
+pub const PIT_FREQUENCY: u32 = 1193182;
+// A reload value of 0 stands for 65536, the power-up default
+pub const PIT_MAX_DIVISOR: u32 = 65536;
+
+static PIT_CHANNEL0: Port = Port::new(0x40);
+static PIT_CHANNEL2: Port = Port::new(0x42);
+static PIT_COMMAND: Port = Port::new(0x43);
+// Keyboard controller port B drives the gate of channel 2 and reads its output
+static PIT_PORT_B: Port = Port::new(0x61);
+
+const PIT_SELECT_CHANNEL0: u8 = 0x00;
+const PIT_SELECT_CHANNEL2: u8 = 0x80;
+const PIT_ACCESS_LATCH: u8 = 0x00;
+const PIT_ACCESS_LOHI: u8 = 0x30;
+const PIT_MODE_INTERRUPT_ON_COUNT: u8 = 0x00;
+const PIT_MODE_RATE_GENERATOR: u8 = 0x04;
+
+const PORT_B_GATE2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT2: u8 = 1 << 5;
+
+pub fn divisor_for(hz: u32) -> u32 {
+    if hz == 0 {
+        return PIT_MAX_DIVISOR;
+    }
+    ((PIT_FREQUENCY + hz / 2) / hz).clamp(1, PIT_MAX_DIVISOR)
+}
+
+fn load(channel: &Port, divisor: u32) {
+    channel.out8(divisor as u8);
+    channel.out8((divisor >> 8) as u8);
+}
+
+// Channel 0 raises IRQ0 every divisor input cycles
+pub fn start_periodic(divisor: u32) {
+    PIT_COMMAND.out8(PIT_SELECT_CHANNEL0 | PIT_ACCESS_LOHI | PIT_MODE_RATE_GENERATOR);
+    load(&PIT_CHANNEL0, divisor);
+}
+
+// Channel 0 counts down from the divisor to 1
+pub fn read_counter() -> u32 {
+    PIT_COMMAND.out8(PIT_SELECT_CHANNEL0 | PIT_ACCESS_LATCH);
+    let lo = PIT_CHANNEL0.in8() as u32;
+    let hi = PIT_CHANNEL0.in8() as u32;
+    match hi << 8 | lo {
+        0 => PIT_MAX_DIVISOR,
+        count => count,
+    }
+}
+
+// Channel 2 is only wired to the speaker, which stays off, so it is free
+// for measuring short intervals
+pub fn start_one_shot(count: u16) {
+    PIT_PORT_B.out8((PIT_PORT_B.in8() & !PORT_B_SPEAKER) | PORT_B_GATE2);
+    PIT_COMMAND.out8(PIT_SELECT_CHANNEL2 | PIT_ACCESS_LOHI | PIT_MODE_INTERRUPT_ON_COUNT);
+    load(&PIT_CHANNEL2, count as u32);
+}
+
+pub fn one_shot_done() -> bool {
+    PIT_PORT_B.in8() & PORT_B_OUT2 != 0
+}
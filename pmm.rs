@@ -0,0 +1,183 @@
+use crate::paging::IDENTITY_MAP_END;
+use crate::spinlock::IrqSpinLock;
+
+pub const FRAME_SIZE: u32 = 4096;
+// Only frames the kernel can reach through the identity mapping are managed
+const MAX_FRAMES: usize = (IDENTITY_MAP_END / FRAME_SIZE) as usize;
+const BITMAP_WORDS: usize = MAX_FRAMES / 32;
+
+// One bit per 4 KiB frame, set bit means free
+struct FrameAllocator {
+    bitmap: [u32; BITMAP_WORDS],
+    // Set for frames of available memory that are not reserved, only those
+    // may be handed out and freed
+    usable: [u32; BITMAP_WORDS],
+    free_frames: usize,
+    total_frames: usize,
+    next_free_hint: usize,
+    // Available memory above the managed range in bytes
+    unmanaged_bytes: u64,
+}
+
+static FRAMES: IrqSpinLock<FrameAllocator> = IrqSpinLock::new(FrameAllocator {
+    bitmap: [0; BITMAP_WORDS],
+    usable: [0; BITMAP_WORDS],
+    free_frames: 0,
+    total_frames: 0,
+    next_free_hint: 0,
+    unmanaged_bytes: 0,
+});
+
+fn frame_idx(addr: u32) -> usize {
+    (addr / FRAME_SIZE) as usize
+}
+
+fn frame_addr(idx: usize) -> u32 {
+    idx as u32 * FRAME_SIZE
+}
+
+impl FrameAllocator {
+    fn is_free(&self, idx: usize) -> bool {
+        self.bitmap[idx / 32] & (1 << (idx % 32)) != 0
+    }
+
+    fn is_usable(&self, idx: usize) -> bool {
+        idx < MAX_FRAMES && self.usable[idx / 32] & (1 << (idx % 32)) != 0
+    }
+
+    fn mark_free(&mut self, idx: usize) {
+        self.bitmap[idx / 32] |= 1 << (idx % 32);
+        self.free_frames += 1;
+    }
+
+    fn mark_used(&mut self, idx: usize) {
+        self.bitmap[idx / 32] &= !(1 << (idx % 32));
+        self.free_frames -= 1;
+    }
+}
+
+fn clamp_to_frames(addr: u64) -> usize {
+    let idx = addr / FRAME_SIZE as u64;
+    if idx > MAX_FRAMES as u64 {
+        MAX_FRAMES
+    } else {
+        idx as usize
+    }
+}
+
+pub fn add_free_region(base: u64, length: u64) {
+    // Only whole frames inside the region can be used
+    let first = clamp_to_frames(base + FRAME_SIZE as u64 - 1);
+    let last = clamp_to_frames(base + length);
+    let limit = MAX_FRAMES as u64 * FRAME_SIZE as u64;
+    let mut frames = FRAMES.lock();
+    frames.unmanaged_bytes += (base + length).saturating_sub(base.max(limit));
+    for idx in first..last {
+        frames.usable[idx / 32] |= 1 << (idx % 32);
+        if !frames.is_free(idx) {
+            frames.mark_free(idx);
+            frames.total_frames += 1;
+        }
+    }
+}
+
+pub fn reserve_region(start: u64, end: u64) {
+    // Every frame touched by the region is taken away
+    let first = clamp_to_frames(start);
+    let last = clamp_to_frames(end + FRAME_SIZE as u64 - 1);
+    let mut frames = FRAMES.lock();
+    for idx in first..last {
+        frames.usable[idx / 32] &= !(1 << (idx % 32));
+        if frames.is_free(idx) {
+            frames.mark_used(idx);
+            frames.total_frames -= 1;
+        }
+    }
+}
+
+pub fn alloc_frame() -> Option<u32> {
+    let mut frames = FRAMES.lock();
+    if frames.free_frames == 0 {
+        return None;
+    }
+    let mut word = frames.next_free_hint / 32;
+    for _ in 0..BITMAP_WORDS {
+        if frames.bitmap[word] != 0 {
+            let idx = word * 32 + frames.bitmap[word].trailing_zeros() as usize;
+            frames.mark_used(idx);
+            frames.next_free_hint = idx;
+            return Some(frame_addr(idx));
+        }
+        word = (word + 1) % BITMAP_WORDS;
+    }
+    None
+}
+
+pub fn alloc_contiguous(count: usize) -> Option<u32> {
+    if count == 0 {
+        return None;
+    }
+    let mut frames = FRAMES.lock();
+    if frames.free_frames < count {
+        return None;
+    }
+    let mut run_start = 0usize;
+    let mut run_len = 0usize;
+    for idx in 0..MAX_FRAMES {
+        if !frames.is_free(idx) {
+            run_len = 0;
+            continue;
+        }
+        if run_len == 0 {
+            run_start = idx;
+        }
+        run_len += 1;
+        if run_len == count {
+            for i in run_start..run_start + count {
+                frames.mark_used(i);
+            }
+            return Some(frame_addr(run_start));
+        }
+    }
+    None
+}
+
+pub fn free_frame(addr: u32) {
+    if addr % FRAME_SIZE != 0 {
+        panic!("free of unaligned frame 0x{:08X}", addr);
+    }
+    let idx = frame_idx(addr);
+    let mut frames = FRAMES.lock();
+    // Reserved, outside of available memory or above the managed range, so
+    // never handed out
+    if !frames.is_usable(idx) {
+        drop(frames);
+        panic!("free of reserved frame 0x{:08X}", addr);
+    }
+    if frames.is_free(idx) {
+        drop(frames);
+        panic!("double free of frame 0x{:08X}", addr);
+    }
+    frames.mark_free(idx);
+    if idx < frames.next_free_hint {
+        frames.next_free_hint = idx;
+    }
+}
+
+pub fn free_contiguous(addr: u32, count: usize) {
+    for i in 0..count as u32 {
+        free_frame(addr + i * FRAME_SIZE);
+    }
+}
+
+pub fn free_frames() -> usize {
+    FRAMES.lock().free_frames
+}
+
+pub fn total_frames() -> usize {
+    FRAMES.lock().total_frames
+}
+
+pub fn unmanaged_bytes() -> u64 {
+    FRAMES.lock().unmanaged_bytes
+}
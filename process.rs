@@ -0,0 +1,130 @@
+use crate::elf::{self, ElfError};
+use crate::paging::AddressSpace;
+use crate::sched::{self, ThreadError, ThreadId};
+use crate::spinlock::{IrqSpinLock, SpinLock};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub type ProcessId = u32;
+
+// Threads of a process share its address space
+pub struct Process {
+    id: ProcessId,
+    address_space: AddressSpace,
+    // Which user stack slots of the address space belong to a thread, looked
+    // at by the page fault handler
+    stack_slots: IrqSpinLock<Vec<bool>>,
+}
+
+static NEXT_PROCESS_ID: SpinLock<ProcessId> = SpinLock::new(1);
+
+// Bytes of argument strings and pointers placed on the initial stack
+const ARG_MAX: usize = 4096;
+
+#[derive(Debug)]
+pub enum ExecError {
+    OutOfMemory,
+    ArgumentsTooLong,
+    Elf(ElfError),
+    Thread(ThreadError),
+}
+
+impl From<ElfError> for ExecError {
+    fn from(err: ElfError) -> ExecError {
+        ExecError::Elf(err)
+    }
+}
+
+impl From<ThreadError> for ExecError {
+    fn from(err: ThreadError) -> ExecError {
+        ExecError::Thread(err)
+    }
+}
+
+impl Process {
+    pub fn new() -> Option<Arc<Process>> {
+        let address_space = AddressSpace::new()?;
+        let id = {
+            let mut next_id = NEXT_PROCESS_ID.lock();
+            let id = *next_id;
+            *next_id += 1;
+            id
+        };
+        Some(Arc::new(Process {
+            id,
+            address_space,
+            stack_slots: IrqSpinLock::new(Vec::new()),
+        }))
+    }
+
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    // The lowest free slot below max
+    pub fn alloc_stack_slot(&self, max: usize) -> Option<usize> {
+        let mut slots = self.stack_slots.lock();
+        match slots.iter().position(|used| !used) {
+            Some(slot) => {
+                slots[slot] = true;
+                Some(slot)
+            },
+            None if slots.len() < max => {
+                slots.push(true);
+                Some(slots.len() - 1)
+            },
+            None => None,
+        }
+    }
+
+    pub fn free_stack_slot(&self, slot: usize) {
+        self.stack_slots.lock()[slot] = false;
+    }
+
+    pub fn stack_slot_in_use(&self, slot: usize) -> bool {
+        self.stack_slots.lock().get(slot).copied().unwrap_or(false)
+    }
+}
+
+fn initial_stack_size(args: &[&[u8]]) -> usize {
+    let strings: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    // argc, the argv pointers with their terminator and an empty envp
+    (args.len() + 3) * 4 + strings
+}
+
+// i386 System V process entry layout: esp points at argc, followed by the
+// argv pointers, a null pointer, an empty environment and the strings
+fn initial_stack(top: u32, args: &[&[u8]]) -> Vec<u8> {
+    let start = (top - initial_stack_size(args) as u32) & !0xF;
+    let mut stack = vec![0u8; (top - start) as usize];
+    let mut string_addr = start + (args.len() as u32 + 3) * 4;
+    stack[..4].copy_from_slice(&(args.len() as u32).to_ne_bytes());
+    for (i, arg) in args.iter().enumerate() {
+        let ptr = 4 * (i + 1);
+        stack[ptr..ptr + 4].copy_from_slice(&string_addr.to_ne_bytes());
+        let offset = (string_addr - start) as usize;
+        stack[offset..offset + arg.len()].copy_from_slice(arg);
+        string_addr += arg.len() as u32 + 1;
+    }
+    stack
+}
+
+// Starts an ELF executable in a new process, the arguments are separated by spaces
+pub fn exec(image: &[u8], cmdline: &[u8]) -> Result<ThreadId, ExecError> {
+    let args: Vec<&[u8]> = cmdline.split(|c| *c == b' ').filter(|arg| !arg.is_empty()).collect();
+    if initial_stack_size(&args) > ARG_MAX {
+        return Err(ExecError::ArgumentsTooLong);
+    }
+    let process = Process::new().ok_or(ExecError::OutOfMemory)?;
+    let entry = elf::load(process.address_space(), image)?;
+    // Named after the program like a command in ps
+    let name = args.first().map(|path| path.rsplit(|c| *c == b'/').next().unwrap_or(path));
+    let name = String::from_utf8_lossy(name.unwrap_or(&[]));
+    Ok(sched::create_user_thread(&name, &process, entry, |top| initial_stack(top, &args))?)
+}
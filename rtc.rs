@@ -0,0 +1,352 @@
+use core::fmt::{Display, Formatter, Result};
+use core::time::Duration;
+use crate::ioport::Port;
+use crate::spinlock::IrqSpinLock;
+use crate::time;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_SECONDS_ALARM: u8 = 0x01;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_MINUTES_ALARM: u8 = 0x03;
+const RTC_HOURS: u8 = 0x04;
+const RTC_HOURS_ALARM: u8 = 0x05;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+const RTC_STATUS_C: u8 = 0x0C;
+// Not standardized, but where ACPI FADTs commonly point
+const RTC_CENTURY: u8 = 0x32;
+
+const RTC_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RTC_A_RATE_MASK: u8 = 0x0F;
+const RTC_B_PERIODIC: u8 = 1 << 6;
+const RTC_B_ALARM: u8 = 1 << 5;
+const RTC_B_UPDATE_ENDED: u8 = 1 << 4;
+const RTC_B_BINARY: u8 = 1 << 2;
+const RTC_B_24_HOUR: u8 = 1 << 1;
+const RTC_C_PERIODIC: u8 = 1 << 6;
+const RTC_C_ALARM: u8 = 1 << 5;
+const RTC_C_UPDATE_ENDED: u8 = 1 << 4;
+const RTC_HOUR_PM: u8 = 1 << 7;
+// Alarm fields with both top bits set match any value
+const RTC_ALARM_ANY: u8 = 0xC0;
+
+// Base frequency of the periodic interrupt divider
+const RTC_BASE_HZ: u32 = 32768;
+// Lower rates are reserved for the oscillator
+const RTC_MIN_RATE: u8 = 3;
+// An update takes about 2 ms, port reads about a microsecond each, a clock
+// that never finishes is read anyway and the reads are compared instead
+const RTC_UPDATE_MAX_POLLS: u32 = 10_000;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+// Days between 1970-01-01 and the given date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    pub fn unix_seconds(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let seconds = self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        (days * SECS_PER_DAY as i64 + seconds).max(0) as u64
+    }
+
+    pub fn from_unix_seconds(seconds: u64) -> DateTime {
+        let (year, month, day) = civil_from_days((seconds / SECS_PER_DAY) as i64);
+        let rem = seconds % SECS_PER_DAY;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+}
+
+// ISO 8601 in UTC, which is what the RTC is assumed to hold
+impl Display for DateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// The index register selects what the data register accesses, so both
+// are only used with the lock held
+struct Cmos {
+    index: Port,
+    data: Port,
+}
+
+impl Cmos {
+    fn read(&self, reg: u8) -> u8 {
+        self.index.out8(reg);
+        self.data.in8()
+    }
+
+    fn write(&self, reg: u8, val: u8) {
+        self.index.out8(reg);
+        self.data.out8(val);
+    }
+
+    fn update(&self, reg: u8, clear: u8, set: u8) {
+        let val = self.read(reg);
+        self.write(reg, (val & !clear) | set);
+    }
+}
+
+struct Handlers {
+    periodic: Option<fn()>,
+    alarm: Option<fn()>,
+}
+
+// Wall-clock seconds at the given monotonic time
+struct RealtimeBase {
+    unix_seconds: u64,
+    monotonic_ns: u64,
+}
+
+static CMOS: IrqSpinLock<Cmos> = IrqSpinLock::new(Cmos {
+    index: Port::new(0x70),
+    data: Port::new(0x71),
+});
+// Only changed with the CMOS locked as well, so the interrupt handler sees
+// them together with the register settings they belong to
+static HANDLERS: IrqSpinLock<Handlers> = IrqSpinLock::new(Handlers {
+    periodic: None,
+    alarm: None,
+});
+static REALTIME_BASE: IrqSpinLock<RealtimeBase> = IrqSpinLock::new(RealtimeBase {
+    unix_seconds: 0,
+    monotonic_ns: 0,
+});
+
+fn from_bcd(val: u8) -> u8 {
+    (val >> 4) * 10 + (val & 0x0F)
+}
+
+fn to_bcd(val: u8) -> u8 {
+    (val / 10) << 4 | val % 10
+}
+
+#[derive(PartialEq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw(cmos: &Cmos) -> RawTime {
+    for _ in 0..RTC_UPDATE_MAX_POLLS {
+        if cmos.read(RTC_STATUS_A) & RTC_A_UPDATE_IN_PROGRESS == 0 {
+            break;
+        }
+    }
+    RawTime {
+        second: cmos.read(RTC_SECONDS),
+        minute: cmos.read(RTC_MINUTES),
+        hour: cmos.read(RTC_HOURS),
+        day: cmos.read(RTC_DAY),
+        month: cmos.read(RTC_MONTH),
+        year: cmos.read(RTC_YEAR),
+        century: cmos.read(RTC_CENTURY),
+    }
+}
+
+// Decodes an hour register, 12 hour clocks mark the afternoon in the top bit
+fn decode_hour(raw: u8, binary: bool, hour_24: bool) -> u8 {
+    let pm = !hour_24 && raw & RTC_HOUR_PM != 0;
+    let raw = if hour_24 { raw } else { raw & !RTC_HOUR_PM };
+    let hour = if binary { raw } else { from_bcd(raw) };
+    match (hour_24, pm, hour) {
+        (true, _, hour) => hour,
+        (false, false, 12) => 0,
+        (false, true, 12) => 12,
+        (false, true, hour) => hour + 12,
+        (false, false, hour) => hour,
+    }
+}
+
+fn encode_hour(hour: u8, binary: bool, hour_24: bool) -> u8 {
+    let (hour, pm) = match (hour_24, hour) {
+        (true, hour) => (hour, 0),
+        (false, 0) => (12, 0),
+        (false, 12) => (12, RTC_HOUR_PM),
+        (false, hour) if hour > 12 => (hour - 12, RTC_HOUR_PM),
+        (false, hour) => (hour, 0),
+    };
+    (if binary { hour } else { to_bcd(hour) }) | pm
+}
+
+pub fn read_time() -> DateTime {
+    // The registers may change between reads, so read until two agree
+    let (raw, status_b) = {
+        let cmos = CMOS.lock();
+        let mut raw = read_raw(&cmos);
+        loop {
+            let again = read_raw(&cmos);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(RTC_STATUS_B))
+    };
+    let binary = status_b & RTC_B_BINARY != 0;
+    let hour_24 = status_b & RTC_B_24_HOUR != 0;
+    let decode = |val| if binary { val } else { from_bcd(val) };
+    let century = match decode(raw.century) {
+        century @ 19..=99 => century as u16,
+        _ => 20,
+    };
+    DateTime {
+        year: century * 100 + decode(raw.year) as u16,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour: decode_hour(raw.hour, binary, hour_24),
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    }
+}
+
+fn sync_realtime() {
+    let unix_seconds = read_time().unix_seconds();
+    let monotonic_ns = time::monotonic_ns();
+    *REALTIME_BASE.lock() = RealtimeBase { unix_seconds, monotonic_ns };
+}
+
+// The seconds register is only exact right after an update, so the clock
+// is synced again when the first update-ended interrupt arrives
+pub fn init() {
+    sync_realtime();
+    let cmos = CMOS.lock();
+    // Flags left over from before boot would block further interrupts
+    cmos.read(RTC_STATUS_C);
+    cmos.update(RTC_STATUS_B, 0, RTC_B_UPDATE_ENDED);
+}
+
+// Since the Unix epoch
+pub fn realtime() -> Duration {
+    let (seconds, base) = {
+        let base = REALTIME_BASE.lock();
+        (base.unix_seconds, base.monotonic_ns)
+    };
+    Duration::from_secs(seconds) + Duration::from_nanos(time::monotonic_ns().saturating_sub(base))
+}
+
+pub fn now() -> DateTime {
+    DateTime::from_unix_seconds(realtime().as_secs())
+}
+
+// Rounded down to a power of two fraction of 32768 Hz, between 2 and 8192 Hz
+pub fn enable_periodic(hz: u32, handler: fn()) {
+    let mut rate = RTC_MIN_RATE;
+    while rate < RTC_A_RATE_MASK && RTC_BASE_HZ >> (rate - 1) > hz {
+        rate += 1;
+    }
+    let cmos = CMOS.lock();
+    let mut handlers = HANDLERS.lock();
+    cmos.update(RTC_STATUS_A, RTC_A_RATE_MASK, rate);
+    cmos.update(RTC_STATUS_B, 0, RTC_B_PERIODIC);
+    handlers.periodic = Some(handler);
+}
+
+pub fn disable_periodic() {
+    let cmos = CMOS.lock();
+    cmos.update(RTC_STATUS_B, RTC_B_PERIODIC, 0);
+    HANDLERS.lock().periodic = None;
+}
+
+// Fields left as None match any value, so an alarm with only the minute and
+// second set goes off every hour
+pub fn set_alarm(hour: Option<u8>, minute: Option<u8>, second: Option<u8>, handler: fn()) {
+    let cmos = CMOS.lock();
+    let mut handlers = HANDLERS.lock();
+    // No interrupt for a half written alarm time
+    cmos.update(RTC_STATUS_B, RTC_B_ALARM, 0);
+    let status_b = cmos.read(RTC_STATUS_B);
+    let binary = status_b & RTC_B_BINARY != 0;
+    let hour_24 = status_b & RTC_B_24_HOUR != 0;
+    let encode = |val: Option<u8>| match val {
+        Some(val) if binary => val,
+        Some(val) => to_bcd(val),
+        None => RTC_ALARM_ANY,
+    };
+    cmos.write(RTC_SECONDS_ALARM, encode(second));
+    cmos.write(RTC_MINUTES_ALARM, encode(minute));
+    cmos.write(RTC_HOURS_ALARM, match hour {
+        Some(hour) => encode_hour(hour, binary, hour_24),
+        None => RTC_ALARM_ANY,
+    });
+    cmos.update(RTC_STATUS_B, 0, RTC_B_ALARM);
+    handlers.alarm = Some(handler);
+}
+
+pub fn clear_alarm() {
+    let cmos = CMOS.lock();
+    cmos.update(RTC_STATUS_B, RTC_B_ALARM, 0);
+    HANDLERS.lock().alarm = None;
+}
+
+// IRQ 8, status C has to be read or the RTC raises no further interrupts
+pub fn handle_interrupt() {
+    // Called without the locks so they may change the handlers
+    let (flags, periodic, alarm) = {
+        let cmos = CMOS.lock();
+        let handlers = HANDLERS.lock();
+        (cmos.read(RTC_STATUS_C), handlers.periodic, handlers.alarm)
+    };
+    if flags & RTC_C_UPDATE_ENDED != 0 {
+        sync_realtime();
+        CMOS.lock().update(RTC_STATUS_B, RTC_B_UPDATE_ENDED, 0);
+    }
+    if flags & RTC_C_PERIODIC != 0 {
+        if let Some(handler) = periodic {
+            handler();
+        }
+    }
+    if flags & RTC_C_ALARM != 0 {
+        if let Some(handler) = alarm {
+            handler();
+        }
+    }
+}
@@ -1,18 +1,90 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::asm;
 use core::marker::Copy;
 use core::clone::Clone;
-use core::fmt::{Display, Formatter, Result};
-use core::ptr::addr_of;
+use core::fmt::{Display, Formatter, Result, Write};
+use core::ptr::{addr_of, addr_of_mut};
+use core::time::Duration;
+use crate::cmdline::ParamValue;
+use crate::cpu;
+use crate::idt;
+use crate::paging;
+use crate::paging::{AddressSpace, MapError};
+use crate::pmm;
+use crate::process::{Process, ProcessId};
+use crate::spinlock::{IrqSpinLock, IrqSpinLockGuard};
+use crate::time;
+use crate::timer;
+
+pub type ThreadId = u32;
+// Higher runs first under the fixed-priority policy
+pub type Priority = u8;
+
+pub const PRIORITY_MIN: Priority = 0;
+pub const PRIORITY_DEFAULT: Priority = 16;
+pub const PRIORITY_MAX: Priority = 31;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Policy {
+    RoundRobin,
+    FixedPriority,
+    // Threads start in the top level and drop a level whenever they are
+    // preempted, the static priorities are not used
+    Mlfq,
+}
+
+impl ParamValue for Policy {
+    fn parse(value: Option<&'static str>) -> Option<Policy> {
+        match value? {
+            "rr" => Some(Policy::RoundRobin),
+            "priority" => Some(Policy::FixedPriority),
+            "mlfq" => Some(Policy::Mlfq),
+            _ => None,
+        }
+    }
+}
+
+crate::kernel_param!(static mut SCHED_POLICY: Policy = Policy::Mlfq, "sched.policy");
+
+const MLFQ_LEVELS: u8 = 4;
+// All threads go back to the top level this often so none starves for good
+const MLFQ_BOOST_PERIOD: Duration = Duration::from_secs(1);
+
+// Timer ticks a thread runs before others get their turn, doubled for
+// each MLFQ level further down
+crate::kernel_param!(static mut SCHED_QUANTUM: u32 = 10, "sched.quantum");
+
+fn policy() -> Policy {
+    unsafe { SCHED_POLICY }
+}
 
 #[derive(Copy, Clone)]
-enum ThreadState {
+pub enum ThreadState {
     Running,
     Waiting,
+    // Until the monotonic clock reaches the deadline in ns
+    Sleeping(u64),
     Stopped,
 }
 
+impl Display for ThreadState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.pad(match self {
+            ThreadState::Running => "running",
+            ThreadState::Waiting => "waiting",
+            ThreadState::Sleeping(_) => "sleeping",
+            ThreadState::Stopped => "stopped",
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
-pub struct Thread {
+pub struct Registers {
     eax: u32,
     ebx: u32,
     ecx: u32,
@@ -25,11 +97,58 @@ pub struct Thread {
     eflags: u32,
     cs: u32,
     ss: u32,
+}
 
-    state: ThreadState,
+impl Registers {
+    const fn new(eip: u32, esp: u32, cs: u32, ss: u32) -> Registers {
+        Registers {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            esi: 0,
+            edi: 0,
+            ebp: 0,
+            esp,
+            eip,
+            eflags: X86_EFLAGS_BASE | X86_EFLAGS_IF,
+            cs,
+            ss,
+        }
+    }
+}
+
+impl Registers {
+    // As pushed by the interrupt entry code, see idt::X86_INT_STATE_*
+    pub fn from_int_state(int_state: *const u32) -> Registers {
+        unsafe {
+            let cs = *int_state.offset(10);
+            let (esp, ss) = if cs & 0b11 == 0b11 {
+                // interrupted user-mode
+                (*int_state.offset(12), *int_state.offset(13))
+            } else {
+                // interrupted kernel-mode
+                (int_state.offset(12) as u32, KERNEL_DS)
+            };
+            Registers {
+                eax: *int_state.offset(6),
+                ebx: *int_state.offset(5),
+                ecx: *int_state.offset(4),
+                edx: *int_state.offset(3),
+                esi: *int_state.offset(2),
+                edi: *int_state.offset(1),
+                ebp: *int_state.offset(0),
+                esp,
+                eip: *int_state.offset(9),
+                eflags: *int_state.offset(11),
+                cs,
+                ss,
+            }
+        }
+    }
 }
 
-impl Display for Thread {
+impl Display for Registers {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         write!(f, "\
             eax 0x{:08X} ebx 0x{:08X} ecx 0x{:08X} edx 0x{:08X}\n\
@@ -41,6 +160,84 @@ impl Display for Thread {
     }
 }
 
+struct Thread {
+    id: ThreadId,
+    name: String,
+    regs: Registers,
+
+    state: ThreadState,
+    priority: Priority,
+    // MLFQ level, 0 is the top
+    level: u8,
+    // Ticks left before the thread is preempted, refilled once used up
+    quantum_left: u32,
+    exit: Arc<ExitStatus>,
+    detached: bool,
+
+    // Timer ticks that found the thread on the CPU
+    user_ticks: u64,
+    kernel_ticks: u64,
+    // Times the thread was switched to from another one
+    switches: u64,
+    created_ns: u64,
+
+    // Kernel threads run on it, user threads enter the kernel on it
+    stack: Option<Box<[u8]>>,
+    user_stack_slot: Option<usize>,
+    // Kernel threads have none and run in the kernel page directory
+    process: Option<Arc<Process>>,
+}
+
+impl Thread {
+    fn page_directory(&self) -> u32 {
+        match &self.process {
+            Some(process) => process.address_space().page_directory(),
+            None => paging::kernel_page_directory(),
+        }
+    }
+
+    fn kernel_stack_top(&self) -> Option<u32> {
+        self.stack.as_ref().map(|stack| stack.as_ptr() as u32 + stack.len() as u32)
+    }
+
+    fn release_stacks(&mut self) {
+        self.stack = None;
+        if let (Some(slot), Some(process)) = (self.user_stack_slot.take(), &self.process) {
+            unmap_user_stack(process, slot);
+        }
+    }
+}
+
+// Outlives the thread for those joining it
+struct ExitStatus {
+    code: IrqSpinLock<Option<u32>>,
+    joiners: WaitQueue,
+}
+
+impl ExitStatus {
+    fn new() -> ExitStatus {
+        ExitStatus {
+            code: IrqSpinLock::new(None),
+            joiners: WaitQueue::new(),
+        }
+    }
+
+    // Only the first code counts
+    fn set(&self, code: u32) {
+        let mut slot = self.code.lock();
+        if slot.is_none() {
+            *slot = Some(code);
+        }
+        self.joiners.wake_all();
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        self.release_stacks();
+    }
+}
+
 #[naked]
 extern "C" fn idle_proc() -> ! {
     unsafe {
@@ -52,28 +249,24 @@ extern "C" fn idle_proc() -> ! {
     }
 }
 
-const MAX_THREADS: usize = 5;
 const STACK_SIZE: usize = 16*1024;
-static mut THREADS: [Option<Thread>; MAX_THREADS] = [None; MAX_THREADS];
-static mut STACKS: [[u8; STACK_SIZE]; MAX_THREADS] = [[0; STACK_SIZE]; MAX_THREADS];
-static mut CURRENT_THREAD_IDX: usize = 0;
-static mut CURRENT_THREAD_COUNT: usize = 0;
-static mut IDLE_THREAD: Thread = Thread {
-    eax: 0,
-    ebx: 0,
-    ecx: 0,
-    edx: 0,
-    esi: 0,
-    edi: 0,
-    ebp: 0,
-    esp: 0,
-    eip: 0,
-    eflags: X86_EFLAGS_BASE | X86_EFLAGS_IF,
-    cs: KERNEL_CS,
-    ss: KERNEL_DS,
-
-    state: ThreadState::Running,
-};
+
+struct Scheduler {
+    threads: Vec<Thread>,
+    current: Option<ThreadId>,
+    next_id: ThreadId,
+    // A thread that ranks above the current one became runnable
+    need_resched: bool,
+    idle_ticks: u64,
+}
+
+static SCHEDULER: IrqSpinLock<Scheduler> = IrqSpinLock::new(Scheduler {
+    threads: Vec::new(),
+    current: None,
+    next_id: 1,
+    need_resched: false,
+    idle_ticks: 0,
+});
 const IDLE_STACK_SIZE: usize = 4*1024;
 static mut IDLE_STACK: [u8; IDLE_STACK_SIZE] = [0; IDLE_STACK_SIZE];
 
@@ -84,7 +277,7 @@ const X86_EFLAGS_AF: u32 = 1 << 4;
 const X86_EFLAGS_ZF: u32 = 1 << 6;
 const X86_EFLAGS_SF: u32 = 1 << 7;
 const X86_EFLAGS_TF: u32 = 1 << 8;
-const X86_EFLAGS_IF: u32 = 1 << 9;
+pub const X86_EFLAGS_IF: u32 = 1 << 9;
 const X86_EFLAGS_DF: u32 = 1 << 10;
 const X86_EFLAGS_OF: u32 = 1 << 11;
 const X86_EFLAGS_IOPL0: u32 = 0 << 12;
@@ -104,171 +297,674 @@ const KERNEL_DS: u32 = 0x10;
 const USER_CS: u32 = 0x1B;
 const USER_DS: u32 = 0x23;
 
-pub fn create_kernel_thread(entry: *const ()) {
-    unsafe {
-        if CURRENT_THREAD_COUNT == MAX_THREADS {
-            panic!("No more space for threads");
-        }
-        let stack = &STACKS[CURRENT_THREAD_COUNT] as *const u8;
-        let thread = Thread {
-            eax: 0,
-            ebx: 0,
-            ecx: 0,
-            edx: 0,
-            esi: 0,
-            edi: 0,
-            ebp: 0,
-            esp: stack.add(STACK_SIZE) as u32,
-            eip: entry as u32,
-            eflags: X86_EFLAGS_BASE | X86_EFLAGS_IF,
-            cs: KERNEL_CS,
-            ss: KERNEL_DS,
+// User stacks are stacked downwards from the end of user space with a guard page between them,
+// only the top STACK_SIZE bytes are mapped up front, the rest grows on page faults
+const USER_STACKS_TOP: u32 = paging::USER_END;
+const USER_STACK_MAX_SIZE: u32 = 1024*1024;
+const USER_STACK_STRIDE: u32 = USER_STACK_MAX_SIZE + paging::PAGE_SIZE;
+// Per process, every address space has slots of its own
+const USER_STACK_SLOTS_MAX: usize = 64;
+// Nothing else may be mapped from here up to the end of user space
+pub const USER_STACKS_BOTTOM: u32 = USER_STACKS_TOP - USER_STACK_SLOTS_MAX as u32 * USER_STACK_STRIDE;
 
-            state: ThreadState::Running,
-        };
-        THREADS[CURRENT_THREAD_COUNT] = Some(thread);
-        CURRENT_THREAD_COUNT += 1;
+#[derive(Debug)]
+pub enum ThreadError {
+    // All user stack slots of the process are taken
+    TooManyThreads,
+    Map(MapError),
+}
+
+impl From<MapError> for ThreadError {
+    fn from(err: MapError) -> ThreadError {
+        ThreadError::Map(err)
     }
 }
 
-pub fn create_user_thread(entry: *const ()) {
-    unsafe {
-        if CURRENT_THREAD_COUNT == MAX_THREADS {
-            panic!("No more space for threads");
+fn user_stack_top(slot: usize) -> u32 {
+    USER_STACKS_TOP - slot as u32 * USER_STACK_STRIDE
+}
+
+fn map_user_stack_page(space: &AddressSpace, page: u32) -> core::result::Result<(), MapError> {
+    let frame = pmm::alloc_frame().ok_or(MapError::OutOfMemory)?;
+    if let Err(err) = space.map_page(page, frame, paging::X86_PTE_WRITABLE | paging::X86_PTE_USER) {
+        pmm::free_frame(frame);
+        return Err(err);
+    }
+    Ok(())
+}
+
+// On failure whatever was mapped of the stack is released with its slot
+fn map_user_stack(process: &Process) -> core::result::Result<(usize, u32), ThreadError> {
+    let slot = process.alloc_stack_slot(USER_STACK_SLOTS_MAX).ok_or(ThreadError::TooManyThreads)?;
+    let top = user_stack_top(slot);
+    let mut page = top - STACK_SIZE as u32;
+    while page < top {
+        if let Err(err) = map_user_stack_page(process.address_space(), page) {
+            unmap_user_stack(process, slot);
+            return Err(err.into());
         }
-        let stack = &STACKS[CURRENT_THREAD_COUNT] as *const u8;
-        let thread = Thread {
-            eax: 0,
-            ebx: 0,
-            ecx: 0,
-            edx: 0,
-            esi: 0,
-            edi: 0,
-            ebp: 0,
-            esp: stack.add(STACK_SIZE) as u32,
-            eip: entry as u32,
-            eflags: X86_EFLAGS_BASE | X86_EFLAGS_IF,
-            cs: USER_CS,
-            ss: USER_DS,
+        page += paging::PAGE_SIZE;
+    }
+    Ok((slot, top))
+}
 
-            state: ThreadState::Running,
-        };
-        THREADS[CURRENT_THREAD_COUNT] = Some(thread);
-        CURRENT_THREAD_COUNT += 1;
+fn unmap_user_stack(process: &Process, slot: usize) {
+    let space = process.address_space();
+    let top = user_stack_top(slot);
+    let mut page = top - USER_STACK_MAX_SIZE;
+    while page < top {
+        if let Some(frame) = space.unmap_page(page) {
+            pmm::free_frame(frame);
+        }
+        page += paging::PAGE_SIZE;
     }
+    process.free_stack_slot(slot);
 }
 
-fn set_thread_state(idx: usize, state: ThreadState) {
-    unsafe {
-        if idx < CURRENT_THREAD_COUNT {
-            if let Some(ref mut thread) = THREADS[idx] {
-                match thread.state {
-                    ThreadState::Stopped => {},
-                    ThreadState::Waiting |
-                    ThreadState::Running => thread.state = state,
+// Tells whether the address lies in the growable part of a live user stack
+// of the current process
+pub fn is_user_stack_area(addr: u32) -> bool {
+    if addr >= USER_STACKS_TOP || addr < USER_STACKS_BOTTOM {
+        return false;
+    }
+    let slot = ((USER_STACKS_TOP - addr - 1) / USER_STACK_STRIDE) as usize;
+    let mut sched = SCHEDULER.lock();
+    let in_use = match sched.current_mut().and_then(|thread| thread.process.as_ref()) {
+        Some(process) => process.stack_slot_in_use(slot),
+        None => false,
+    };
+    in_use && addr >= user_stack_top(slot) - USER_STACK_MAX_SIZE
+}
+
+impl Scheduler {
+    fn thread_idx(&self, id: ThreadId) -> Option<usize> {
+        self.threads.iter().position(|thread| thread.id == id)
+    }
+
+    fn thread_mut(&mut self, id: ThreadId) -> Option<&mut Thread> {
+        self.threads.iter_mut().find(|thread| thread.id == id)
+    }
+
+    fn current_mut(&mut self) -> Option<&mut Thread> {
+        let id = self.current?;
+        self.thread_mut(id)
+    }
+
+    // The idle thread gives way to any thread
+    fn wake(&mut self, idx: usize) {
+        self.threads[idx].state = ThreadState::Running;
+        let woken = rank(&self.threads[idx]);
+        let current = self.current.and_then(|id| self.thread_idx(id));
+        if current.map_or(true, |current| woken > rank(&self.threads[current])) {
+            self.need_resched = true;
+        }
+    }
+
+    // Round robin among the runnable threads of the best rank, starting after
+    // the current one
+    fn next_idx(&self, current_idx: Option<usize>) -> Option<usize> {
+        let count = self.threads.len();
+        let start = current_idx.map_or(0, |idx| idx + 1);
+        let mut best: Option<(usize, u8)> = None;
+        for i in 0..count {
+            let idx = (start + i) % count;
+            let thread = &self.threads[idx];
+            if let ThreadState::Running = thread.state {
+                let rank = rank(thread);
+                if best.map_or(true, |(_, best_rank)| rank > best_rank) {
+                    best = Some((idx, rank));
                 }
             }
         }
+        best.map(|(idx, _)| idx)
+    }
+
+    // Stacks and the process of stopped threads are released once nothing
+    // runs on them anymore, only the id and exit code are kept for join
+    // unless the thread is detached
+    fn reap_stopped_threads(&mut self) {
+        let current = self.current;
+        self.threads.retain_mut(|thread| {
+            if let ThreadState::Stopped = thread.state {
+                if Some(thread.id) != current {
+                    if thread.detached {
+                        return false;
+                    }
+                    thread.release_stacks();
+                    thread.process = None;
+                }
+            }
+            true
+        });
     }
 }
 
-pub fn stop_thread(i: usize) {
-    set_thread_state(i, ThreadState::Stopped);
+fn rank(thread: &Thread) -> u8 {
+    match policy() {
+        Policy::RoundRobin => 0,
+        Policy::FixedPriority => thread.priority,
+        Policy::Mlfq => MLFQ_LEVELS - 1 - thread.level,
+    }
 }
 
-pub fn suspend_thread(i: usize) {
-    set_thread_state(i, ThreadState::Waiting);
+fn add_thread(mut thread: Thread) -> ThreadId {
+    thread.created_ns = time::monotonic_ns();
+    let mut sched = SCHEDULER.lock();
+    let id = sched.next_id;
+    sched.next_id += 1;
+    thread.id = id;
+    sched.threads.push(thread);
+    id
 }
 
-pub fn resume_thread(i: usize) {
-    set_thread_state(i, ThreadState::Running);
+// Entry functions of kernel threads return here
+extern "C" fn thread_return() -> ! {
+    thread_exit(0);
 }
 
-pub fn current() -> &'static Thread {
-    unsafe { THREADS[CURRENT_THREAD_IDX].as_ref().unwrap() }
+pub type ThreadEntry = extern "C" fn(usize);
+
+// Initial frame as seen by the cdecl entry function: return address, then the argument
+pub fn create_kernel_thread(name: &str, entry: ThreadEntry, arg: usize) -> ThreadId {
+    let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    let ret_addr = (thread_return as usize as u32).to_ne_bytes();
+    stack[STACK_SIZE - 8..STACK_SIZE - 4].copy_from_slice(&ret_addr);
+    stack[STACK_SIZE - 4..].copy_from_slice(&(arg as u32).to_ne_bytes());
+    let stack_top = stack.as_ptr() as u32 + STACK_SIZE as u32 - 8;
+    add_thread(Thread {
+        id: 0,
+        name: String::from(name),
+        regs: Registers::new(entry as u32, stack_top, KERNEL_CS, KERNEL_DS),
+
+        state: ThreadState::Running,
+        priority: PRIORITY_DEFAULT,
+        level: 0,
+        quantum_left: 0,
+        exit: Arc::new(ExitStatus::new()),
+        detached: false,
+
+        user_ticks: 0,
+        kernel_ticks: 0,
+        switches: 0,
+        created_ns: 0,
+
+        stack: Some(stack),
+        user_stack_slot: None,
+        process: None,
+    })
 }
 
-unsafe fn next_idx(current_idx: usize) -> (usize, *const Thread) {
-    let mut idx = current_idx;
-    loop {
-        idx = (idx + 1) % MAX_THREADS;
-        if let Some(thread) = &THREADS[idx] {
-            if let ThreadState::Running = thread.state {
-                return (idx, thread);
-            }
+// The initial stack contents are built for the given stack top and end there,
+// the thread starts with esp pointing at their first byte
+pub fn create_user_thread(name: &str, process: &Arc<Process>, entry: u32,
+                          init_stack: impl FnOnce(u32) -> Vec<u8>) -> core::result::Result<ThreadId, ThreadError> {
+    let space = process.address_space();
+    let (slot, stack_top) = map_user_stack(process)?;
+    let kernel_stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    let contents = init_stack(stack_top);
+    assert!(contents.len() <= STACK_SIZE, "initial user stack too large");
+    let stack_top = stack_top - contents.len() as u32;
+    space.write(stack_top, &contents);
+    Ok(add_thread(Thread {
+        id: 0,
+        name: String::from(name),
+        regs: Registers::new(entry, stack_top, USER_CS, USER_DS),
+
+        state: ThreadState::Running,
+        priority: PRIORITY_DEFAULT,
+        level: 0,
+        quantum_left: 0,
+        exit: Arc::new(ExitStatus::new()),
+        detached: false,
+
+        user_ticks: 0,
+        kernel_ticks: 0,
+        switches: 0,
+        created_ns: 0,
+
+        stack: Some(kernel_stack),
+        user_stack_slot: Some(slot),
+        process: Some(process.clone()),
+    }))
+}
+
+type Closure = Box<dyn FnOnce() + Send + 'static>;
+
+extern "C" fn closure_entry(arg: usize) {
+    let f = unsafe { Box::from_raw(arg as *mut Closure) };
+    f();
+}
+
+pub fn spawn<F: FnOnce() + Send + 'static>(name: &str, f: F) -> ThreadId {
+    // Boxed twice to get a thin pointer that fits into the argument
+    let closure: Box<Closure> = Box::new(Box::new(f));
+    create_kernel_thread(name, closure_entry, Box::into_raw(closure) as usize)
+}
+
+fn set_thread_state(id: ThreadId, state: ThreadState) {
+    let mut sched = SCHEDULER.lock();
+    if let Some(idx) = sched.thread_idx(id) {
+        match (sched.threads[idx].state, state) {
+            (ThreadState::Stopped, _) => {},
+            (ThreadState::Waiting, ThreadState::Running) |
+            (ThreadState::Sleeping(_), ThreadState::Running) => sched.wake(idx),
+            _ => sched.threads[idx].state = state,
         }
-        if idx == current_idx {
-            return (MAX_THREADS, addr_of!(IDLE_THREAD));
+    }
+}
+
+pub fn set_priority(id: ThreadId, priority: Priority) {
+    if let Some(thread) = SCHEDULER.lock().thread_mut(id) {
+        thread.priority = priority.clamp(PRIORITY_MIN, PRIORITY_MAX);
+    }
+}
+
+pub fn priority(id: ThreadId) -> Option<Priority> {
+    SCHEDULER.lock().thread_mut(id).map(|thread| thread.priority)
+}
+
+// The thread exits with code 0
+pub fn stop_thread(id: ThreadId) {
+    let exit = SCHEDULER.lock().thread_mut(id).map(|thread| thread.exit.clone());
+    set_thread_state(id, ThreadState::Stopped);
+    if let Some(exit) = exit {
+        exit.set(0);
+    }
+}
+
+pub fn suspend_thread(id: ThreadId) {
+    set_thread_state(id, ThreadState::Waiting);
+}
+
+pub fn resume_thread(id: ThreadId) {
+    set_thread_state(id, ThreadState::Running);
+}
+
+// Only wakes the thread from the sleep the timer was set up for
+fn wake_sleeper(id: ThreadId, deadline: u64) {
+    let mut sched = SCHEDULER.lock();
+    if let Some(idx) = sched.thread_idx(id) {
+        if let ThreadState::Sleeping(until) = sched.threads[idx].state {
+            if until == deadline {
+                sched.wake(idx);
+            }
         }
     }
 }
 
-pub fn save_current_state(int_state: *const u32) {
-    unsafe {
-        if CURRENT_THREAD_IDX == MAX_THREADS {
+// Returns early when the thread is resumed before the deadline
+pub fn sleep(duration: Duration) {
+    let deadline = time::monotonic_ns().saturating_add(time::duration_ns(duration));
+    let id = match current_id() {
+        Some(id) => id,
+        // Nothing else to run outside of a thread
+        None => {
+            while time::monotonic_ns() < deadline {
+                core::hint::spin_loop();
+            }
             return;
+        },
+    };
+    if duration.is_zero() {
+        yield_now();
+        return;
+    }
+    // Not preempted before the timer that wakes the thread is in place
+    idt::without_interrupts(|| {
+        set_thread_state(id, ThreadState::Sleeping(deadline));
+        timer::add_oneshot(duration, move || wake_sleeper(id, deadline));
+    });
+    yield_now();
+}
+
+// Threads blocked until some condition changes
+pub struct WaitQueue {
+    waiters: IrqSpinLock<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: IrqSpinLock::new(VecDeque::new()),
         }
-        if let Some(ref mut thread) = THREADS[CURRENT_THREAD_IDX] {
-            thread.ebp = *int_state.offset(0);
-            thread.edi = *int_state.offset(1);
-            thread.esi = *int_state.offset(2);
-            thread.edx = *int_state.offset(3);
-            thread.ecx = *int_state.offset(4);
-            thread.ebx = *int_state.offset(5);
-            thread.eax = *int_state.offset(6);
-            thread.eip = *int_state.offset(9);
-            thread.cs = *int_state.offset(10);
-            thread.eflags = *int_state.offset(11);
-            if thread.cs & 0b11 == 0b11 {
-                // interrupted user-mode
-                thread.esp = *int_state.offset(12);
-                thread.ss = *int_state.offset(13);
-            } else {
-                // interrupted kernel-mode
-                thread.esp = int_state.offset(12) as u32;
-                thread.ss = KERNEL_DS;
+    }
+
+    // The guard protects what the condition looks at and is released while the
+    // thread blocks, wakers have to change that state under the same lock
+    pub fn wait_while<'a, T>(&self, mut guard: IrqSpinLockGuard<'a, T>,
+                             mut condition: impl FnMut(&mut T) -> bool) -> IrqSpinLockGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    // Blocks until woken by wake_one or wake_all, or resumed by resume_thread
+    pub fn wait<'a, T>(&self, mut guard: IrqSpinLockGuard<'a, T>) -> IrqSpinLockGuard<'a, T> {
+        let id = current_id().expect("cannot block outside of a thread");
+        // Queued before the lock is dropped, so a wakeup cannot get lost in between
+        self.waiters.lock().push_back(id);
+        set_thread_state(id, ThreadState::Waiting);
+        IrqSpinLockGuard::unlocked(&mut guard, yield_now);
+        // Still queued when something other than this queue woke the thread
+        self.waiters.lock().retain(|&waiter| waiter != id);
+        guard
+    }
+
+    // Skips threads that were stopped or resumed while they waited
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        while let Some(id) = waiters.pop_front() {
+            if wake_waiter(id) {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn wake_all(&self) -> usize {
+        let mut waiters = self.waiters.lock();
+        let mut count = 0;
+        while let Some(id) = waiters.pop_front() {
+            if wake_waiter(id) {
+                count += 1;
             }
         }
+        count
+    }
+}
+
+fn wake_waiter(id: ThreadId) -> bool {
+    let mut sched = SCHEDULER.lock();
+    if let Some(idx) = sched.thread_idx(id) {
+        if let ThreadState::Waiting = sched.threads[idx].state {
+            sched.wake(idx);
+            return true;
+        }
+    }
+    false
+}
+
+// Interrupts stay off until the next thread runs, so joiners woken here
+// cannot see the thread before it is stopped
+pub fn thread_exit(code: u32) -> ! {
+    idt::disable_interrupts();
+    let exit = match SCHEDULER.lock().current_mut() {
+        Some(thread) => thread.exit.clone(),
+        None => panic!("thread_exit called outside of a thread"),
+    };
+    exit.set(code);
+    drop(exit);
+    if let Some(thread) = SCHEDULER.lock().current_mut() {
+        thread.state = ThreadState::Stopped;
+    }
+    invoke_scheduler();
+}
+
+// Blocks until the thread exits and frees what is left of it
+pub fn join(id: ThreadId) -> Option<u32> {
+    if current_id() == Some(id) {
+        return None;
+    }
+    let exit = {
+        let mut sched = SCHEDULER.lock();
+        let thread = sched.thread_mut(id)?;
+        if thread.detached {
+            return None;
+        }
+        thread.exit.clone()
+    };
+    let code = exit.joiners.wait_while(exit.code.lock(), |code| code.is_none()).unwrap();
+    detach(id);
+    Some(code)
+}
+
+// A detached thread is freed right after it stops and cannot be joined
+pub fn detach(id: ThreadId) {
+    let mut sched = SCHEDULER.lock();
+    if let Some(idx) = sched.thread_idx(id) {
+        sched.threads[idx].detached = true;
+        if let ThreadState::Stopped = sched.threads[idx].state {
+            if sched.current != Some(id) {
+                sched.threads.remove(idx);
+            }
+        }
+    }
+}
+
+// Saves the state through a software interrupt, which ends up in yield_current
+pub fn yield_now() {
+    unsafe {
+        asm!("int 0x81");
+    }
+}
+
+// As saved when the current thread last entered the kernel through an
+// interrupt, those of the idle thread outside of threads
+pub fn current_registers() -> Registers {
+    match SCHEDULER.lock().current_mut() {
+        Some(thread) => thread.regs,
+        None => idle_registers(),
+    }
+}
+
+pub fn current_id() -> Option<ThreadId> {
+    SCHEDULER.lock().current
+}
+
+pub fn current_process_id() -> Option<ProcessId> {
+    let mut sched = SCHEDULER.lock();
+    sched.current_mut()?.process.as_ref().map(|process| process.id())
+}
+
+pub fn save_current_state(int_state: *const u32) {
+    let mut sched = SCHEDULER.lock();
+    if let Some(thread) = sched.current_mut() {
+        thread.regs = Registers::from_int_state(int_state);
     }
 }
 
+extern "C" {
+    static mut _tss: [u32; 26];
+}
+
+const TSS_ESP0: usize = 1;
+
+// Traps from ring 3 and sysenter both land on the kernel stack of the thread
+fn set_kernel_entry_stack(top: u32) {
+    unsafe {
+        let tss = addr_of_mut!(_tss) as *mut u32;
+        *tss.add(TSS_ESP0) = top;
+    }
+    cpu::wrmsr(cpu::IA32_SYSENTER_ESP, top as u64);
+}
+
 extern "C" {
     fn restore_thread(eax: u32, ebx: u32, ecx: u32, edx: u32,
                       esi: u32, edi: u32, ebp: u32, esp: u32,
                       eip: u32, eflags: u32, cs: u32, ss: u32) -> !;
 }
 
-fn switch_to_thread(t: *const Thread) -> ! {
+fn idle_registers() -> Registers {
+    let stack_top = unsafe { addr_of!(IDLE_STACK) as u32 } + IDLE_STACK_SIZE as u32;
+    Registers::new(idle_proc as usize as u32, stack_top, KERNEL_CS, KERNEL_DS)
+}
+
+// Whatever is needed of the next thread is copied out, the scheduler lock
+// cannot be held across the switch
+struct SwitchTarget {
+    regs: Registers,
+    kernel_stack_top: Option<u32>,
+    page_directory: u32,
+}
+
+fn switch_to_thread(target: SwitchTarget) -> ! {
+    if let Some(top) = target.kernel_stack_top {
+        set_kernel_entry_stack(top);
+    }
+    paging::switch_page_directory(target.page_directory);
+    let regs = target.regs;
     unsafe {
-        restore_thread((*t).eax, (*t).ebx, (*t).ecx, (*t).edx,
-                       (*t).esi, (*t).edi, (*t).ebp, (*t).esp,
-                       (*t).eip, (*t).eflags, (*t).cs, (*t).ss);
+        restore_thread(regs.eax, regs.ebx, regs.ecx, regs.edx,
+                       regs.esi, regs.edi, regs.ebp, regs.esp,
+                       regs.eip, regs.eflags, regs.cs, regs.ss);
     }
 }
 
 pub fn invoke_scheduler() -> ! {
-    unsafe {
-        let start_idx = if CURRENT_THREAD_IDX == MAX_THREADS { 0 } else { CURRENT_THREAD_IDX };
-        let (idx, thread) = next_idx(start_idx);
-        CURRENT_THREAD_IDX = idx;
-        switch_to_thread(thread);
+    let target = {
+        let mut sched = SCHEDULER.lock();
+        sched.reap_stopped_threads();
+        sched.need_resched = false;
+        let current_idx = sched.current.and_then(|id| sched.thread_idx(id));
+        match sched.next_idx(current_idx) {
+            Some(idx) => {
+                let previous = sched.current;
+                let thread = &mut sched.threads[idx];
+                if thread.quantum_left == 0 {
+                    thread.quantum_left = quantum(thread);
+                }
+                if previous != Some(thread.id) {
+                    thread.switches += 1;
+                }
+                let id = thread.id;
+                let target = SwitchTarget {
+                    regs: thread.regs,
+                    kernel_stack_top: thread.kernel_stack_top(),
+                    page_directory: thread.page_directory(),
+                };
+                sched.current = Some(id);
+                target
+            },
+            None => {
+                sched.current = None;
+                SwitchTarget {
+                    regs: idle_registers(),
+                    kernel_stack_top: None,
+                    page_directory: paging::kernel_page_directory(),
+                }
+            },
+        }
+    };
+    switch_to_thread(target);
+}
+
+fn quantum(thread: &Thread) -> u32 {
+    let quantum = unsafe { SCHED_QUANTUM }.max(1);
+    match policy() {
+        Policy::Mlfq => quantum.saturating_mul(1 << thread.level),
+        _ => quantum,
     }
 }
 
-pub fn start_scheduler() -> ! {
-    unsafe {
-        if let Some(ref thread) = THREADS[0] {
-            switch_to_thread(thread);
-        } else {
-            switch_to_thread(addr_of!(IDLE_THREAD));
+// Called on every timer interrupt, tells whether the current thread has to
+// make room because its quantum is used up or a better thread woke up
+pub fn timer_tick(from_user: bool) -> bool {
+    let mut sched = SCHEDULER.lock();
+    let need_resched = sched.need_resched;
+    match sched.current_mut() {
+        Some(thread) => {
+            if from_user {
+                thread.user_ticks += 1;
+            } else {
+                thread.kernel_ticks += 1;
+            }
+            thread.quantum_left = thread.quantum_left.saturating_sub(1);
+            thread.quantum_left == 0 || need_resched
+        },
+        None => {
+            sched.idle_ticks += 1;
+            need_resched
+        },
+    }
+}
+
+// Only a thread that used up its whole quantum drops an MLFQ level
+pub fn preempt() -> ! {
+    if let Some(thread) = SCHEDULER.lock().current_mut() {
+        if let (Policy::Mlfq, ThreadState::Running, 0) = (policy(), thread.state, thread.quantum_left) {
+            thread.level = (thread.level + 1).min(MLFQ_LEVELS - 1);
         }
     }
+    invoke_scheduler();
 }
 
-pub fn init_scheduler() {
-    unsafe {
-        IDLE_THREAD.eip = idle_proc as usize as u32;
-        let stack = addr_of!(IDLE_STACK) as *const u8;
-        IDLE_THREAD.esp = stack.add(IDLE_STACK_SIZE) as u32;
+// The thread yielded or blocked, the rest of its quantum is given up
+pub fn yield_current() -> ! {
+    if let Some(thread) = SCHEDULER.lock().current_mut() {
+        thread.quantum_left = 0;
+    }
+    invoke_scheduler();
+}
+
+fn boost_threads() {
+    for thread in SCHEDULER.lock().threads.iter_mut() {
+        thread.level = 0;
+    }
+}
+
+pub fn start_scheduler() -> ! {
+    if policy() == Policy::Mlfq {
+        timer::add_periodic(MLFQ_BOOST_PERIOD, boost_threads);
+    }
+    invoke_scheduler();
+}
+
+// A snapshot of one thread for ps and friends
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: String,
+    pub state: ThreadState,
+    pub priority: Priority,
+    pub level: u8,
+    pub process: Option<ProcessId>,
+    pub user_ticks: u64,
+    pub kernel_ticks: u64,
+    pub switches: u64,
+    pub created_ns: u64,
+}
+
+pub fn threads() -> Vec<ThreadInfo> {
+    let sched = SCHEDULER.lock();
+    sched.threads.iter().map(|thread| ThreadInfo {
+        id: thread.id,
+        name: thread.name.clone(),
+        state: thread.state,
+        priority: thread.priority,
+        level: thread.level,
+        process: thread.process.as_ref().map(|process| process.id()),
+        user_ticks: thread.user_ticks,
+        kernel_ticks: thread.kernel_ticks,
+        switches: thread.switches,
+        created_ns: thread.created_ns,
+    }).collect()
+}
+
+// Timer ticks spent with no thread to run
+pub fn idle_ticks() -> u64 {
+    SCHEDULER.lock().idle_ticks
+}
+
+fn ticks_ms(ticks: u64) -> u64 {
+    ticks.saturating_mul(time::tick_ns()) / 1_000_000
+}
+
+// Times are in ms, the CPU times have the resolution of a timer tick
+pub fn dump_threads(out: &mut dyn Write) -> Result {
+    let threads = threads();
+    let idle = idle_ticks();
+    write!(out, "  TID   PID PRI LVL STATE      USER    SYS   CSW  STARTED NAME\n")?;
+    for thread in &threads {
+        write!(out, "{:5} ", thread.id)?;
+        match thread.process {
+            Some(pid) => write!(out, "{:5} ", pid)?,
+            None => write!(out, "    - ")?,
+        }
+        write!(out, "{:3} {:3} {:8} {:6} {:6} {:5} {:8} {}\n",
+               thread.priority, thread.level, thread.state,
+               ticks_ms(thread.user_ticks), ticks_ms(thread.kernel_ticks),
+               thread.switches, thread.created_ns / 1_000_000, thread.name)?;
     }
+    write!(out, "{:5} {:>5} {:>3} {:>3} {:8} {:6} {:6} {:>5} {:8} idle\n",
+           0, "-", "-", "-", "-", 0, ticks_ms(idle), "-", 0)
 }
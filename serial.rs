@@ -1,4 +1,5 @@
 use crate::ioport::Port;
+use crate::sync::InputBuffer;
 
 const SERIAL_BASE: u16 = 0x3f8;
 
@@ -13,11 +14,23 @@ static SERIAL_LSR: Port = Port::new(SERIAL_BASE + 5);
 static SERIAL_MSR: Port = Port::new(SERIAL_BASE + 6);
 static SERIAL_SR: Port = Port::new(SERIAL_BASE + 7);
 
+const SERIAL_CLOCK: u32 = 115200;
+const SERIAL_LSR_DATA_READY: u8 = 0x01;
+
+static RX_BUFFER: InputBuffer<256> = InputBuffer::new();
+
+crate::kernel_param!(static mut SERIAL_BAUD: u32 = SERIAL_CLOCK, "serial.baud");
+
 pub fn serial_init() {
+    // Rates the UART cannot divide down to fall back to the maximum
+    let divisor = match unsafe { SERIAL_BAUD } {
+        0 => 1,
+        baud => (SERIAL_CLOCK / baud).clamp(1, 0xFFFF),
+    };
     SERIAL_IER.out8(0x00);
     SERIAL_LCR.out8(0x80);
-    SERIAL_DLAB_DIV_LSB.out8(0x01);
-    SERIAL_DLAB_DIV_MSB.out8(0x00);
+    SERIAL_DLAB_DIV_LSB.out8(divisor as u8);
+    SERIAL_DLAB_DIV_MSB.out8((divisor >> 8) as u8);
     SERIAL_LCR.out8(0x03);
     SERIAL_II.out8(0xC7);
     SERIAL_MCR.out8(0x0B);
@@ -31,12 +44,37 @@ pub fn serial_init() {
 }
 
 pub fn write_str(s: &str) {
-    for b in s.bytes() {
+    write_bytes(s.as_bytes());
+}
+
+pub fn write_bytes(bytes: &[u8]) {
+    for &b in bytes {
         while (SERIAL_LSR.in8() & 0x20) == 0 {}
         SERIAL_DR.out8(b);
     }
 }
 
-pub fn get_byte() -> u8 {
-    SERIAL_DR.in8()
+// IRQ 4, the FIFO may hold several bytes by now
+pub fn handle_interrupt() {
+    while SERIAL_LSR.in8() & SERIAL_LSR_DATA_READY != 0 {
+        RX_BUFFER.push(SERIAL_DR.in8());
+    }
+}
+
+// Blocks the thread until a byte arrives
+pub fn read_byte() -> u8 {
+    RX_BUFFER.pop()
+}
+
+pub fn try_read_byte() -> Option<u8> {
+    RX_BUFFER.try_pop()
+}
+
+pub struct Serial;
+
+impl core::fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_str(s);
+        Ok(())
+    }
 }
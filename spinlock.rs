@@ -0,0 +1,160 @@
+use core::cell::UnsafeCell;
+use core::hint;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::idt;
+
+// Busy-waits for the lock, only for data that is never touched from interrupt
+// handlers, otherwise a handler spinning on a lock held by the code it
+// interrupted never returns
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+// Test and test-and-set, waiting CPUs only read the cache line
+fn acquire(locked: &AtomicBool) {
+    while locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        while locked.load(Ordering::Relaxed) {
+            hint::spin_loop();
+        }
+    }
+}
+
+fn try_acquire(locked: &AtomicBool) -> bool {
+    locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+}
+
+fn release(locked: &AtomicBool) {
+    locked.store(false, Ordering::Release);
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> SpinLock<T> {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        acquire(&self.locked);
+        SpinLockGuard { lock: self }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        if try_acquire(&self.locked) {
+            Some(SpinLockGuard { lock: self })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        release(&self.lock.locked);
+    }
+}
+
+// Disables interrupts on the local CPU for as long as the lock is held, for
+// data shared with interrupt handlers
+pub struct IrqSpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for IrqSpinLock<T> {}
+unsafe impl<T: Send> Send for IrqSpinLock<T> {}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    lock: &'a IrqSpinLock<T>,
+    // Whether interrupts were enabled before the lock was taken
+    interrupts: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> IrqSpinLock<T> {
+        IrqSpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let interrupts = idt::interrupts_enabled();
+        idt::disable_interrupts();
+        acquire(&self.locked);
+        IrqSpinLockGuard { lock: self, interrupts }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let interrupts = idt::interrupts_enabled();
+        idt::disable_interrupts();
+        if try_acquire(&self.locked) {
+            return Some(IrqSpinLockGuard { lock: self, interrupts });
+        }
+        if interrupts {
+            idt::enable_interrupts();
+        }
+        None
+    }
+}
+
+impl<'a, T> IrqSpinLockGuard<'a, T> {
+    // Releases the lock while f runs, e.g. to block on a wait queue
+    pub fn unlocked<R>(guard: &mut IrqSpinLockGuard<'a, T>, f: impl FnOnce() -> R) -> R {
+        release(&guard.lock.locked);
+        if guard.interrupts {
+            idt::enable_interrupts();
+        }
+        let ret = f();
+        idt::disable_interrupts();
+        acquire(&guard.lock.locked);
+        ret
+    }
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        release(&self.lock.locked);
+        if self.interrupts {
+            idt::enable_interrupts();
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use core::cell::UnsafeCell;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use crate::sched::WaitQueue;
+use crate::spinlock::IrqSpinLock;
+
+// Blocking primitives for threads, their state sits behind a spin lock that
+// is also what the wait queues check their conditions under
+
+pub struct Mutex<T> {
+    locked: IrqSpinLock<bool>,
+    queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex {
+            locked: IrqSpinLock::new(false),
+            queue: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let mut locked = self.queue.wait_while(self.locked.lock(), |locked| *locked);
+        *locked = true;
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut locked = self.locked.lock();
+        if *locked {
+            return None;
+        }
+        *locked = true;
+        Some(MutexGuard { mutex: self })
+    }
+
+    fn unlock(&self) {
+        *self.locked.lock() = false;
+        self.queue.wake_one();
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+pub struct Semaphore {
+    count: IrqSpinLock<usize>,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: IrqSpinLock::new(count),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        let mut count = self.queue.wait_while(self.count.lock(), |count| *count == 0);
+        *count -= 1;
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.lock();
+        if *count == 0 {
+            return false;
+        }
+        *count -= 1;
+        true
+    }
+
+    // Safe to call from interrupt handlers
+    pub fn release(&self) {
+        *self.count.lock() += 1;
+        self.queue.wake_one();
+    }
+}
+
+pub struct CondVar {
+    queue: WaitQueue,
+}
+
+impl CondVar {
+    pub const fn new() -> CondVar {
+        CondVar {
+            queue: WaitQueue::new(),
+        }
+    }
+
+    // The mutex is released and the thread queued under the spin lock of the
+    // mutex, so a notify from whoever takes the mutex next reaches it
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        mem::forget(guard);
+        let mut locked = mutex.locked.lock();
+        *locked = false;
+        mutex.queue.wake_one();
+        let locked = self.queue.wait(locked);
+        let mut locked = mutex.queue.wait_while(locked, |locked| *locked);
+        *locked = true;
+        MutexGuard { mutex }
+    }
+
+    pub fn wait_while<'a, T>(&self, mut guard: MutexGuard<'a, T>,
+                             mut condition: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.queue.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.queue.wake_all();
+    }
+}
+
+struct RwLockState {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+}
+
+// Waiting writers keep new readers out so they cannot be starved
+pub struct RwLock<T> {
+    state: IrqSpinLock<RwLockState>,
+    read_queue: WaitQueue,
+    write_queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Send> Send for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> RwLock<T> {
+        RwLock {
+            state: IrqSpinLock::new(RwLockState {
+                readers: 0,
+                writer: false,
+                waiting_writers: 0,
+            }),
+            read_queue: WaitQueue::new(),
+            write_queue: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let mut state = self.read_queue.wait_while(self.state.lock(), |state| {
+            state.writer || state.waiting_writers > 0
+        });
+        state.readers += 1;
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let mut state = self.state.lock();
+        state.waiting_writers += 1;
+        let mut state = self.write_queue.wait_while(state, |state| {
+            state.writer || state.readers > 0
+        });
+        state.waiting_writers -= 1;
+        state.writer = true;
+        RwLockWriteGuard { lock: self }
+    }
+
+    fn unlock_read(&self) {
+        let mut state = self.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            self.write_queue.wake_one();
+        }
+    }
+
+    fn unlock_write(&self) {
+        let mut state = self.state.lock();
+        state.writer = false;
+        if state.waiting_writers > 0 {
+            self.write_queue.wake_one();
+        } else {
+            self.read_queue.wake_all();
+        }
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_read();
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_write();
+    }
+}
+
+struct Ring<const N: usize> {
+    data: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let b = self.data[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(b)
+    }
+}
+
+// Bytes from an interrupt handler for threads blocking on them, input that
+// arrives while the buffer is full is dropped
+pub struct InputBuffer<const N: usize> {
+    ring: IrqSpinLock<Ring<N>>,
+    queue: WaitQueue,
+}
+
+impl<const N: usize> InputBuffer<N> {
+    pub const fn new() -> InputBuffer<N> {
+        InputBuffer {
+            ring: IrqSpinLock::new(Ring {
+                data: [0; N],
+                head: 0,
+                len: 0,
+            }),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    pub fn push(&self, b: u8) -> bool {
+        let mut ring = self.ring.lock();
+        if ring.len == N {
+            return false;
+        }
+        let tail = (ring.head + ring.len) % N;
+        ring.data[tail] = b;
+        ring.len += 1;
+        self.queue.wake_one();
+        true
+    }
+
+    pub fn try_pop(&self) -> Option<u8> {
+        self.ring.lock().pop()
+    }
+
+    pub fn pop(&self) -> u8 {
+        let mut ring = self.queue.wait_while(self.ring.lock(), |ring| ring.len == 0);
+        ring.pop().unwrap()
+    }
+}
@@ -0,0 +1,86 @@
+use core::time::Duration;
+use crate::sched;
+use crate::serial;
+use crate::time;
+use crate::usercopy;
+
+// sysenter ABI: eax holds the number, ebx, esi and edi the arguments,
+// the result or a negated error code comes back in eax
+pub const SYS_WRITE: u32 = 0;
+pub const SYS_YIELD: u32 = 1;
+pub const SYS_SLEEP: u32 = 2;
+pub const SYS_EXIT: u32 = 3;
+pub const SYS_GETPID: u32 = 4;
+pub const SYS_GET_TIME: u32 = 5;
+
+pub const EBADF: i32 = 9;
+pub const EFAULT: i32 = 14;
+pub const EINVAL: i32 = 22;
+pub const ENOSYS: i32 = 38;
+
+const STDOUT: u32 = 1;
+const STDERR: u32 = 2;
+
+type SyscallHandler = fn(u32, u32, u32) -> Result<u32, i32>;
+
+static SYSCALL_TABLE: [SyscallHandler; 6] = [
+    sys_write,
+    sys_yield,
+    sys_sleep,
+    sys_exit,
+    sys_getpid,
+    sys_get_time,
+];
+
+fn sys_write(fd: u32, buf: u32, len: u32) -> Result<u32, i32> {
+    if fd != STDOUT && fd != STDERR {
+        return Err(EBADF);
+    }
+    let mut chunk = [0u8; 256];
+    let mut done = 0u32;
+    while done < len {
+        let size = (len - done).min(chunk.len() as u32) as usize;
+        usercopy::copy_from_user(&mut chunk[..size], buf + done)?;
+        serial::write_bytes(&chunk[..size]);
+        done += size as u32;
+    }
+    Ok(len)
+}
+
+fn sys_yield(_: u32, _: u32, _: u32) -> Result<u32, i32> {
+    sched::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(ms: u32, _: u32, _: u32) -> Result<u32, i32> {
+    sched::sleep(Duration::from_millis(ms as u64));
+    Ok(0)
+}
+
+fn sys_exit(code: u32, _: u32, _: u32) -> Result<u32, i32> {
+    sched::thread_exit(code);
+}
+
+fn sys_getpid(_: u32, _: u32, _: u32) -> Result<u32, i32> {
+    sched::current_process_id().ok_or(EINVAL)
+}
+
+fn sys_get_time(_: u32, _: u32, _: u32) -> Result<u32, i32> {
+    Ok(time::uptime().as_millis() as u32)
+}
+
+pub fn dispatch(num: u32, arg1: u32, arg2: u32, arg3: u32) -> u32 {
+    let result = match SYSCALL_TABLE.get(num as usize) {
+        Some(handler) => handler(arg1, arg2, arg3),
+        None => Err(ENOSYS),
+    };
+    match result {
+        Ok(val) => val,
+        Err(err) => (-err) as u32,
+    }
+}
+
+#[no_mangle]
+extern "C" fn handle_syscall(num: u32, arg1: u32, arg2: u32, arg3: u32) -> u32 {
+    dispatch(num, arg1, arg2, arg3)
+}
@@ -0,0 +1,119 @@
+use core::time::Duration;
+use crate::cpu;
+use crate::pit;
+use crate::spinlock::IrqSpinLock;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+const TSC_CALIBRATION_HZ: u32 = 100;
+// Port reads take about a microsecond, so this is far longer than the
+// calibration run and only hit where the PIT output cannot be read back
+const TSC_CALIBRATION_MAX_POLLS: u32 = 1_000_000;
+
+crate::kernel_param!(static mut TIMER_HZ: u32 = 1000, "timer.hz");
+// The TSC may drift with frequency scaling on older CPUs, so it is opt-in
+crate::kernel_param!(static mut CLOCK_TSC: bool = false, "clock.tsc");
+
+struct Clock {
+    ticks: u64,
+    pit_divisor: u32,
+    // Zero while the clock is driven by the PIT alone
+    tsc_hz: u64,
+    tsc_base: u64,
+    last_ns: u64,
+}
+
+static CLOCK: IrqSpinLock<Clock> = IrqSpinLock::new(Clock {
+    ticks: 0,
+    pit_divisor: pit::PIT_MAX_DIVISOR,
+    tsc_hz: 0,
+    tsc_base: 0,
+    last_ns: 0,
+});
+
+// Split to keep the products within 64 bits for clocks of a few GHz
+fn cycles_to_ns(cycles: u64, hz: u64) -> u64 {
+    cycles / hz * NS_PER_SEC + cycles % hz * NS_PER_SEC / hz
+}
+
+// Counts TSC cycles during a one-shot run of the PIT, None when the CPU has
+// no TSC or the run does not end and the clock falls back to the PIT alone
+fn calibrate_tsc() -> Option<u64> {
+    let (_, _, _, features) = cpu::cpuid(1);
+    if features & cpu::CPUID_1_EDX_TSC == 0 {
+        return None;
+    }
+    let count = pit::divisor_for(TSC_CALIBRATION_HZ);
+    pit::start_one_shot(count as u16);
+    let start = cpu::rdtsc();
+    let mut polls = 0;
+    while !pit::one_shot_done() {
+        polls += 1;
+        if polls == TSC_CALIBRATION_MAX_POLLS {
+            return None;
+        }
+    }
+    let cycles = cpu::rdtsc() - start;
+    match cycles * pit::PIT_FREQUENCY as u64 / count as u64 {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+pub fn init() {
+    let divisor = pit::divisor_for(unsafe { TIMER_HZ });
+    let tsc_hz = if unsafe { CLOCK_TSC } { calibrate_tsc().unwrap_or(0) } else { 0 };
+    let mut clock = CLOCK.lock();
+    clock.tsc_hz = tsc_hz;
+    clock.pit_divisor = divisor;
+    clock.ticks = 0;
+    clock.last_ns = 0;
+    if tsc_hz != 0 {
+        clock.tsc_base = cpu::rdtsc();
+    }
+    pit::start_periodic(divisor);
+}
+
+pub fn timer_tick() {
+    CLOCK.lock().ticks += 1;
+}
+
+pub fn ticks() -> u64 {
+    CLOCK.lock().ticks
+}
+
+// Actual rate of the timer interrupt, the divisor rounds it
+pub fn tick_ns() -> u64 {
+    cycles_to_ns(CLOCK.lock().pit_divisor as u64, pit::PIT_FREQUENCY as u64)
+}
+
+pub fn tsc_hz() -> Option<u64> {
+    match CLOCK.lock().tsc_hz {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+pub fn monotonic_ns() -> u64 {
+    let mut clock = CLOCK.lock();
+    let ns = if clock.tsc_hz != 0 {
+        cycles_to_ns(cpu::rdtsc() - clock.tsc_base, clock.tsc_hz)
+    } else {
+        // Interpolated within the current tick from the counter
+        let divisor = clock.pit_divisor;
+        let elapsed = divisor - pit::read_counter().min(divisor);
+        cycles_to_ns(clock.ticks * divisor as u64 + elapsed as u64, pit::PIT_FREQUENCY as u64)
+    };
+    // A tick may still be pending while interrupts are off, the clock
+    // must not go back once it has been seen
+    clock.last_ns = clock.last_ns.max(ns);
+    clock.last_ns
+}
+
+// Saturates instead of going through u128
+pub fn duration_ns(duration: Duration) -> u64 {
+    duration.as_secs().saturating_mul(NS_PER_SEC).saturating_add(duration.subsec_nanos() as u64)
+}
+
+pub fn uptime() -> Duration {
+    Duration::from_nanos(monotonic_ns())
+}
@@ -0,0 +1,122 @@
+use alloc::boxed::Box;
+use alloc::collections::BinaryHeap;
+use core::cmp::Ordering;
+use core::time::Duration;
+use crate::spinlock::IrqSpinLock;
+use crate::time;
+
+pub type TimerId = u64;
+
+// Callbacks run from the timer interrupt with interrupts disabled
+type Callback = Box<dyn FnMut() + Send>;
+
+struct Timer {
+    deadline: u64,
+    id: TimerId,
+    period: Option<u64>,
+    callback: Callback,
+}
+
+// Reversed so that the max-heap yields the earliest deadline first
+impl Ord for Timer {
+    fn cmp(&self, other: &Timer) -> Ordering {
+        (other.deadline, other.id).cmp(&(self.deadline, self.id))
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Timer) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Timer) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Timer {}
+
+struct Timers {
+    heap: Option<BinaryHeap<Timer>>,
+    next_id: TimerId,
+    // The timer whose callback is running, it is out of the heap meanwhile
+    running: Option<TimerId>,
+    running_cancelled: bool,
+}
+
+impl Timers {
+    fn heap(&mut self) -> &mut BinaryHeap<Timer> {
+        self.heap.get_or_insert_with(BinaryHeap::new)
+    }
+}
+
+static TIMERS: IrqSpinLock<Timers> = IrqSpinLock::new(Timers {
+    heap: None,
+    next_id: 1,
+    running: None,
+    running_cancelled: false,
+});
+
+fn add(delay: Duration, period: Option<u64>, callback: Callback) -> TimerId {
+    let deadline = time::monotonic_ns().saturating_add(time::duration_ns(delay));
+    let mut timers = TIMERS.lock();
+    let id = timers.next_id;
+    timers.next_id += 1;
+    timers.heap().push(Timer { deadline, id, period, callback });
+    id
+}
+
+pub fn add_oneshot(delay: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    add(delay, None, Box::new(callback))
+}
+
+// The first call comes after one period
+pub fn add_periodic(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    let period_ns = time::duration_ns(period).max(1);
+    add(period, Some(period_ns), Box::new(callback))
+}
+
+// Returns whether the timer was still pending
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    if timers.running == Some(id) {
+        timers.running_cancelled = true;
+        return true;
+    }
+    let mut pending = core::mem::take(timers.heap()).into_vec();
+    let count = pending.len();
+    pending.retain(|timer| timer.id != id);
+    let found = pending.len() != count;
+    *timers.heap() = BinaryHeap::from(pending);
+    found
+}
+
+// Called from the timer interrupt, callbacks run without the lock so they
+// can add and cancel timers themselves
+pub fn run_expired() {
+    let now = time::monotonic_ns();
+    loop {
+        let mut timer = {
+            let mut timers = TIMERS.lock();
+            let timer = match timers.heap().peek() {
+                Some(timer) if timer.deadline <= now => timers.heap().pop().unwrap(),
+                _ => break,
+            };
+            timers.running = Some(timer.id);
+            timers.running_cancelled = false;
+            timer
+        };
+        (timer.callback)();
+        let mut timers = TIMERS.lock();
+        timers.running = None;
+        if let Some(period) = timer.period {
+            if !timers.running_cancelled {
+                // Missed periods are skipped rather than run in a burst
+                timer.deadline = timer.deadline.saturating_add(period).max(now + 1);
+                timers.heap().push(timer);
+            }
+        }
+    }
+}
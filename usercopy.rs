@@ -0,0 +1,156 @@
+use crate::pagefault;
+use crate::paging;
+use crate::syscall::EFAULT;
+use core::arch::asm;
+use core::ptr::addr_of;
+
+// Pairs of (faulting instruction, continuation) emitted next to every
+// instruction that is allowed to touch user memory
+#[repr(C)]
+struct ExceptionTableEntry {
+    insn: u32,
+    fixup: u32,
+}
+
+extern "C" {
+    static _ex_table_start: ExceptionTableEntry;
+    static _ex_table_end: ExceptionTableEntry;
+}
+
+pub fn search_exception_table(eip: u32) -> Option<u32> {
+    unsafe {
+        let start = addr_of!(_ex_table_start);
+        let end = addr_of!(_ex_table_end);
+        let count = end.offset_from(start) as usize;
+        let table = core::slice::from_raw_parts(start, count);
+        table.iter().find(|entry| entry.insn == eip).map(|entry| entry.fixup)
+    }
+}
+
+// Copies len bytes, returns the number of bytes left uncopied after a fault
+#[naked]
+unsafe extern "C" fn raw_copy(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    unsafe {
+        asm!(
+            "push esi",
+            "push edi",
+            "mov edi, [esp + 4*2 + 4]",
+            "mov esi, [esp + 4*2 + 8]",
+            "mov ecx, [esp + 4*2 + 12]",
+            "2:",
+            "rep movsb",
+            "3:",
+            "mov eax, ecx",
+            "pop edi",
+            "pop esi",
+            "ret",
+            ".pushsection .ex_table, \"a\"",
+            ".long 2b, 3b",
+            ".popsection",
+            options(noreturn));
+    }
+}
+
+// Copies up to max bytes including the terminating zero, returns the length
+// of the string, max if no terminator was found or -1 after a fault
+#[naked]
+unsafe extern "C" fn raw_strncpy(dst: *mut u8, src: *const u8, max: usize) -> isize {
+    unsafe {
+        asm!(
+            "push esi",
+            "push edi",
+            "mov edi, [esp + 4*2 + 4]",
+            "mov esi, [esp + 4*2 + 8]",
+            "mov ecx, [esp + 4*2 + 12]",
+            "xor eax, eax",
+            "4:",
+            "cmp eax, ecx",
+            "jae 6f",
+            "5:",
+            "mov dl, [esi + eax]",
+            "mov [edi + eax], dl",
+            "test dl, dl",
+            "jz 6f",
+            "inc eax",
+            "jmp 4b",
+            "7:",
+            "mov eax, -1",
+            "6:",
+            "pop edi",
+            "pop esi",
+            "ret",
+            ".pushsection .ex_table, \"a\"",
+            ".long 5b, 7b",
+            ".popsection",
+            options(noreturn));
+    }
+}
+
+fn in_user_range(addr: u32, len: usize) -> bool {
+    match addr.checked_add(len as u32) {
+        Some(end) => addr >= paging::USER_START && end <= paging::USER_END,
+        None => false,
+    }
+}
+
+// Pages that are only faulted in on access count as mapped, so do parts of
+// a user stack that the thread could grow into by touching them itself
+fn user_pages_mapped(addr: u32, len: usize, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let end = addr + len as u32;
+    let mut page = addr & !(paging::PAGE_SIZE - 1);
+    while page < end {
+        let mut entry = paging::page_entry(page);
+        if entry & (paging::X86_PTE_PRESENT | paging::X86_PTE_DEMAND) == 0
+            && pagefault::extend_user_stack(page) {
+            entry = paging::page_entry(page);
+        }
+        if entry & paging::X86_PTE_USER == 0 {
+            return false;
+        }
+        if entry & (paging::X86_PTE_PRESENT | paging::X86_PTE_DEMAND) == 0 {
+            return false;
+        }
+        if write && entry & (paging::X86_PTE_WRITABLE | paging::X86_PTE_COW) == 0 {
+            return false;
+        }
+        page += paging::PAGE_SIZE;
+    }
+    true
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: u32) -> Result<(), i32> {
+    if !in_user_range(src, dst.len()) || !user_pages_mapped(src, dst.len(), false) {
+        return Err(EFAULT);
+    }
+    match unsafe { raw_copy(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+pub fn copy_to_user(dst: u32, src: &[u8]) -> Result<(), i32> {
+    if !in_user_range(dst, src.len())
+        || !user_pages_mapped(dst, src.len(), true) {
+        return Err(EFAULT);
+    }
+    match unsafe { raw_copy(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+// Returns the length of the copied string, dst.len() means it was truncated
+pub fn strncpy_from_user(dst: &mut [u8], src: u32) -> Result<usize, i32> {
+    if !in_user_range(src, 1) || !user_pages_mapped(src, 1, false) {
+        return Err(EFAULT);
+    }
+    // The string may end anywhere, faults past its end are caught by the fixup
+    let max = dst.len().min((paging::USER_END - src) as usize);
+    match unsafe { raw_strncpy(dst.as_mut_ptr(), src as *const u8, max) } {
+        -1 => Err(EFAULT),
+        len => Ok(len as usize),
+    }
+}